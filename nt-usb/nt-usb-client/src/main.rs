@@ -1,24 +1,330 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use ansi_term::Colour;
+use serde::Deserialize;
 
-use futures::{future::select, pin_mut, SinkExt};
-use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::{future::select, pin_mut};
+use futures_channel::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender};
 use futures_util::{future::try_join_all, StreamExt};
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
+use tokio_util::codec::Framed;
 
+use usb_proto::{
+    capture_path, perform_handshake, spawn_capture, switch_baud_rate, write_stats_file, BaudRole,
+    Capabilities, CaptureFormat, CaptureRecord, CaptureTap, Connection, ControlCommand,
+    ControlResponse, DeliveryReceipt, FrameAuth, Framing, LinkConfig, LinkDown, LinkInfo,
+    LinkStats, LogLevel, OpenRequest, ProxyCodec, ProxyPacket, Received, DEFAULT_BAUD_RATE,
+    DEFAULT_BAUD_RATES, DEFAULT_COALESCE_WINDOW, DEFAULT_COMPRESSION_THRESHOLD,
+    DEFAULT_CREDIT_WINDOW, DEFAULT_FRAGMENT_SIZE, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_LINK_TIMEOUT,
+    DEFAULT_MAX_FRAME_SIZE, DEFAULT_RETRANSMIT_TIMEOUT,
+};
 
-use usb_proto::{ProxyPacket, ProtoReadable, ProtoWriteable};
+/// Identifies this program to the DS during the USB link handshake
+const BUILD_VERSION: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
 
+/// Where the config is read from at startup, and again whenever the DS asks for it to be reloaded
+const CONFIG_FILE: &str = "./client.config.json";
+
+/// How much to print, which the DS can turn up or down while running
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+#[derive(Deserialize, Clone)]
+struct ClientConfig {
+    serial_port: String,
+    /// Baud rate to open the port at, which the DS has to open its end at too
+    #[serde(default = "default_serial_baud")]
+    serial_baud: u32,
+    /// Baud rates the port can run at, the fastest of which the DS can too is switched to after
+    /// connecting (`[]` to stay at `serial_baud`)
+    #[serde(default = "default_baud_rates")]
+    baud_rates: Vec<u32>,
+    /// NT4 client name the DS connects to the NT server under
+    #[serde(default = "default_nt_client_name")]
+    nt_client_name: String,
+    #[serde(default)]
+    framing: Framing,
+    /// How often to send a heartbeat to the DS, in milliseconds
+    #[serde(default = "default_heartbeat_interval_ms")]
+    heartbeat_interval_ms: u64,
+    /// How long the DS can stay silent before the link is declared dead, in milliseconds
+    #[serde(default = "default_link_timeout_ms")]
+    link_timeout_ms: u64,
+    /// Whether to sequence, acknowledge and resend packets (only used if the DS turns it on too)
+    #[serde(default = "default_reliable")]
+    reliable: bool,
+    /// How long to wait for the DS to acknowledge packets before resending them, in milliseconds
+    #[serde(default = "default_retransmit_timeout_ms")]
+    retransmit_timeout_ms: u64,
+    /// Whether to hold data back until the DS has room for it (only used if the DS turns it on too,
+    /// along with `reliable`)
+    #[serde(default = "default_flow_control")]
+    flow_control: bool,
+    /// How many data packets from the DS can be waiting to be passed on, and how far ahead of us it can get
+    #[serde(default = "default_credit_window")]
+    credit_window: u32,
+    /// Largest frame payload to send to or accept from the DS, in bytes
+    #[serde(default = "default_max_frame_size")]
+    max_frame_size: u32,
+    /// Whether to compress large packets (only used if the DS turns it on too)
+    #[serde(default = "default_compression")]
+    compression: bool,
+    /// Smallest text or binary packet worth compressing, in bytes
+    #[serde(default = "default_compression_threshold")]
+    compression_threshold: usize,
+    /// Largest piece of a big binary packet to send at once, in bytes (only used if the DS turns
+    /// fragmentation on too)
+    #[serde(default = "default_fragment_size")]
+    fragment_size: usize,
+    /// Whether to stamp frames with when they were sent, to measure how long they take to cross the link
+    /// (only used if the DS turns it on too)
+    #[serde(default = "default_timestamps")]
+    timestamps: bool,
+    /// Whether to hold small packets back for a moment so several can share a frame (only used if the
+    /// DS turns it on too)
+    #[serde(default = "default_coalescing")]
+    coalescing: bool,
+    /// Longest a small packet is held back waiting for others to share its frame, in milliseconds
+    #[serde(default = "default_coalesce_window_ms")]
+    coalesce_window_ms: u64,
+    /// Shared secret every frame to and from the DS is signed with, which has to match theirs (`null`
+    /// to send and accept unsigned frames). This keeps out anything without the key, and frames recorded
+    /// off the link and played back into it once the handshake is over.
+    #[serde(default)]
+    auth_key: Option<String>,
+    /// Whether to carry out commands the DS sends over the link, e.g. to reload this config or report stats
+    #[serde(default = "default_remote_control")]
+    remote_control: bool,
+    /// How much to print, `error`, `warn`, `info` or `debug` (the DS can change it while running)
+    #[serde(default)]
+    log_level: LogLevel,
+    /// Where to keep the latest USB link stats as JSON, for looking at after a match (`null` to not bother)
+    #[serde(default = "default_stats_file")]
+    stats_file: Option<String>,
+    /// How often to update the stats file, in milliseconds
+    #[serde(default = "default_stats_interval_ms")]
+    stats_interval_ms: u64,
+    /// Folder to record every byte that crosses the USB link into, one capture file per run, for
+    /// debugging from afterward (`null` to not bother)
+    #[serde(default)]
+    capture_dir: Option<String>,
+    /// File format to record the USB link in, `native` or `pcapng` (for Wireshark)
+    #[serde(default)]
+    capture_format: CaptureFormat,
+}
+
+impl ClientConfig {
+    fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_interval_ms)
+    }
+
+    fn link_timeout(&self) -> Duration {
+        Duration::from_millis(self.link_timeout_ms)
+    }
+
+    fn retransmit_timeout(&self) -> Duration {
+        Duration::from_millis(self.retransmit_timeout_ms)
+    }
+
+    fn coalesce_window(&self) -> Duration {
+        Duration::from_millis(self.coalesce_window_ms)
+    }
+
+    fn stats_interval(&self) -> Duration {
+        Duration::from_millis(self.stats_interval_ms)
+    }
+
+    fn link_config(&self) -> LinkConfig {
+        LinkConfig {
+            heartbeat_interval: self.heartbeat_interval(),
+            link_timeout: self.link_timeout(),
+            retransmit_timeout: self.retransmit_timeout(),
+            credit_window: self.credit_window,
+            compression_threshold: self.compression_threshold,
+            fragment_size: self.fragment_size,
+            coalesce_window: self.coalesce_window(),
+            baud_role: BaudRole::Follower,
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let mut capabilities = Capabilities::HEARTBEAT
+            | Capabilities::MULTIPLEX
+            | Capabilities::WS_PING
+            | Capabilities::OPEN
+            | Capabilities::FRAGMENTATION;
+
+        if self.reliable {
+            capabilities = capabilities | Capabilities::RELIABLE;
+        }
+
+        if self.compression {
+            capabilities = capabilities | Capabilities::COMPRESSION;
+        }
+
+        if !self.baud_rates.is_empty() {
+            capabilities = capabilities | Capabilities::BAUD_SWITCH;
+        }
+
+        if self.flow_control {
+            capabilities = capabilities | Capabilities::FLOW_CONTROL;
+        }
+
+        if self.timestamps {
+            capabilities = capabilities | Capabilities::TIMESTAMPS;
+        }
+
+        if self.coalescing {
+            capabilities = capabilities | Capabilities::BATCHING;
+        }
+
+        if self.remote_control {
+            capabilities = capabilities | Capabilities::CONTROL;
+        }
+
+        capabilities
+    }
+}
+
+fn default_nt_client_name() -> String {
+    String::from("nt-usb-client")
+}
+
+fn default_serial_baud() -> u32 {
+    DEFAULT_BAUD_RATE
+}
+
+fn default_baud_rates() -> Vec<u32> {
+    DEFAULT_BAUD_RATES.to_vec()
+}
+
+fn default_heartbeat_interval_ms() -> u64 {
+    DEFAULT_HEARTBEAT_INTERVAL.as_millis() as u64
+}
+
+fn default_link_timeout_ms() -> u64 {
+    DEFAULT_LINK_TIMEOUT.as_millis() as u64
+}
+
+fn default_reliable() -> bool {
+    true
+}
+
+fn default_retransmit_timeout_ms() -> u64 {
+    DEFAULT_RETRANSMIT_TIMEOUT.as_millis() as u64
+}
+
+fn default_flow_control() -> bool {
+    true
+}
+
+fn default_credit_window() -> u32 {
+    DEFAULT_CREDIT_WINDOW
+}
+
+fn default_max_frame_size() -> u32 {
+    DEFAULT_MAX_FRAME_SIZE
+}
+
+fn default_compression() -> bool {
+    true
+}
+
+fn default_compression_threshold() -> usize {
+    DEFAULT_COMPRESSION_THRESHOLD
+}
+
+fn default_fragment_size() -> usize {
+    DEFAULT_FRAGMENT_SIZE
+}
+
+fn default_timestamps() -> bool {
+    true
+}
+
+fn default_coalescing() -> bool {
+    true
+}
+
+fn default_coalesce_window_ms() -> u64 {
+    DEFAULT_COALESCE_WINDOW.as_millis() as u64
+}
+
+fn default_remote_control() -> bool {
+    true
+}
+
+fn default_stats_file() -> Option<String> {
+    Some(String::from("./client.stats.json"))
+}
+
+fn default_stats_interval_ms() -> u64 {
+    1000
+}
 
 #[tokio::main]
 async fn main() {
+    // Parse configuration
+    let config = match std::fs::read_to_string(CONFIG_FILE) {
+        Ok(config_contents) => {
+            match serde_json::from_str::<ClientConfig>(config_contents.as_str()) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("Could not parse config file!");
+                    eprintln!("{}", e);
+                    std::process::exit(1)
+                }
+            }
+        }
+        Err(_) => ClientConfig {
+            serial_port: String::from("/dev/ttyGS0"),
+            serial_baud: default_serial_baud(),
+            baud_rates: default_baud_rates(),
+            nt_client_name: default_nt_client_name(),
+            framing: Framing::default(),
+            heartbeat_interval_ms: default_heartbeat_interval_ms(),
+            link_timeout_ms: default_link_timeout_ms(),
+            reliable: default_reliable(),
+            retransmit_timeout_ms: default_retransmit_timeout_ms(),
+            flow_control: default_flow_control(),
+            credit_window: default_credit_window(),
+            max_frame_size: default_max_frame_size(),
+            compression: default_compression(),
+            compression_threshold: default_compression_threshold(),
+            fragment_size: default_fragment_size(),
+            timestamps: default_timestamps(),
+            coalescing: default_coalescing(),
+            coalesce_window_ms: default_coalesce_window_ms(),
+            auth_key: None,
+            remote_control: default_remote_control(),
+            log_level: LogLevel::default(),
+            stats_file: default_stats_file(),
+            stats_interval_ms: default_stats_interval_ms(),
+            capture_dir: None,
+            capture_format: CaptureFormat::default(),
+        },
+    };
+
+     set_log_level(config.log_level);
+
      // Create a full duplex channel between the two main async tasks
+     // Nothing the DS sends waits on the nt side, so only credit limits how much of it piles up
      let (usb_tx_to_nt, nt_rx_from_usb) = futures_channel::mpsc::unbounded();
-     let (nt_tx_to_usb, usb_rx_from_nt) = futures_channel::mpsc::unbounded();
+     let (nt_tx_to_usb, usb_rx_from_nt) = futures_channel::mpsc::channel(config.credit_window as usize);
+
+     // Keep count of how the USB link is doing across reconnects
+     let stats = Arc::new(LinkStats::default());
+
+     // Record the USB link from the first byte, if asked to
+     let capture = start_capture(&config);
  
      // Spawn the async tasks
     //  let ws_future = tokio::spawn(create_ws_client(config.clone(), ws_tx, usb_rx));
-     let usb_future = tokio::spawn(create_usb_slave(usb_tx_to_nt, usb_rx_from_nt));
+     tokio::spawn(discard_from_usb(nt_rx_from_usb, nt_tx_to_usb));
+     tokio::spawn(write_stats(config.clone(), stats.clone()));
+     let usb_future = tokio::spawn(create_usb_slave(config, stats, capture, usb_tx_to_nt, usb_rx_from_nt));
  
      // Run both tasks concurrently
      try_join_all(vec![/* ws_future, */ usb_future]).await.unwrap();
@@ -26,107 +332,460 @@ async fn main() {
      panic!("unreachable");
 }
 
+/// Stands in for the nt client until there is one, so packets from the DS don't back up the USB link
+///
+/// Dropping each packet's receipt along with it gives the DS its credit back.
+async fn discard_from_usb(
+    mut rx_from_usb: UnboundedReceiver<(ProxyPacket, DeliveryReceipt)>,
+    _tx_to_usb: Sender<ProxyPacket>,
+) {
+    while rx_from_usb.next().await.is_some() {
+        if log_enabled(LogLevel::Debug) {
+            println!(
+                "{}",
+                Colour::White
+                    .dimmed()
+                    .paint("Dropped a packet from the DS, there's no nt client to pass it to yet")
+            );
+        }
+    }
+}
+
 /// This creates a loop which never ends. It
 async fn create_usb_slave(
-    tx_to_nt: UnboundedSender<ProxyPacket>,
-    mut rx_from_nt: UnboundedReceiver<ProxyPacket>,
+    mut config: ClientConfig,
+    stats: Arc<LinkStats>,
+    capture: Option<UnboundedSender<CaptureRecord>>,
+    tx_to_nt: UnboundedSender<(ProxyPacket, DeliveryReceipt)>,
+    mut rx_from_nt: Receiver<ProxyPacket>,
 ) -> ! {
+    // Rates that didn't work out are left out of later connections, so they aren't tried again every time
+    let mut baud_rates = config.baud_rates.clone();
+
+    // Config the DS had reloaded, waiting for the next connection
+    let reloaded_config = Mutex::new(None);
+
     // Loop continuously while no ports are found or an error condition is met, to always try reconnecting
     loop {
+        // Switch over to the config the DS had reloaded, now that there's a fresh connection to use it on
+        if let Some(reloaded) = reloaded_config.lock().unwrap().take() {
+            config = reloaded;
+            baud_rates = config.baud_rates.clone();
+            set_log_level(config.log_level);
+        }
+
         // Bind to serial device on USB C port
-        let Ok(port) = serialport::new("/dev/ttyGS0", 115_200)
-            .timeout(Duration::from_secs(60 * 60))
-            .open() else {
+        let Ok(port) = tokio_serial::new(config.serial_port.as_str(), config.serial_baud)
+            .open_native_async() else {
                 eprintln!(
                     "{} {}",
-                    Colour::Red.paint("Failed to open serial port `/dev/ttyGS0` at 115,200 baud."),
+                    Colour::Red.paint(format!("Failed to open serial port `{}` at {} baud.", config.serial_port, config.serial_baud)),
                     Colour::White.dimmed().paint("Trying again in 5 seconds...")
                 );
+                tokio::time::sleep(Duration::from_secs(5)).await;
                 continue;
             };
 
-        println!(
-            "{}",
-            Colour::Green.paint(
-                "USB Serial connection with port `/dev/ttyGS0` has been established successfully",
-            )
-        );
-
-        let (Ok(mut reader), Ok(mut writer)) = (port.try_clone(), port.try_clone()) else {
-            eprintln!(
-                "{} {}",
-                Colour::Red.paint("Failed to clone serial port for reading and writing."),
-                Colour::White.dimmed().paint("Trying again in 5 seconds...")
+        if log_enabled(LogLevel::Info) {
+            println!(
+                "{}",
+                Colour::Green.paint(format!(
+                    "USB Serial connection with port `{}` has been established successfully",
+                    config.serial_port
+                ))
             );
-            continue;
+        }
+
+        // Frame packets going in and out of the port
+        let mut codec =
+            ProxyCodec::new(config.framing, config.max_frame_size).with_stats(stats.clone());
+
+        // Only talk to a DS that has the same key, if there is one
+        if let Some(key) = &config.auth_key {
+            codec = codec.with_auth(FrameAuth::new(key.as_bytes()));
+        }
+
+        // Copy everything read from or written to the port into the capture
+        let mut port = CaptureTap::new(port);
+        if let Some(capture) = &capture {
+            port = port.with_sender(capture.clone());
+        }
+        let mut link = Framed::new(port, codec);
+
+        // Make sure the DS speaks our protocol before forwarding anything to it
+        let local = LinkInfo::new(BUILD_VERSION, config.capabilities(), config.max_frame_size)
+            .with_baud_rates(baud_rates.clone());
+        let params = match perform_handshake(&mut link, &local, &stats).await {
+            Ok(params) => params,
+            Err(e) => {
+                eprintln!("{}", e);
+                eprintln!(
+                    "{} {}",
+                    Colour::Red.paint("USB link handshake with the DS failed."),
+                    Colour::White.dimmed().paint("Trying again in 5 seconds...")
+                );
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
         };
 
-        let mut tx_to_nt = tx_to_nt.clone();
+        // Tie every frame from here on to this connection, so none can be played back into it
+        link.codec_mut().bind_auth(local.session, params.peer.session);
 
-        // Read packets from usb serial and send them to the nt client
-        let usb_to_nt = tokio::task::spawn_blocking(move || {
-            loop {
-                // If there was a reading error, break and retry the connection
-                let Ok(num_bytes) = reader.bytes_to_read() else {
+        if log_enabled(LogLevel::Info) {
+            println!(
+                "{}",
+                Colour::Green.paint(format!(
+                    "USB link established with `{}` (protocol v{})",
+                    params.peer.build_version, params.protocol_version
+                ))
+            );
+        }
+
+        // Move up to the fastest baud rate both ends can manage, falling back if the port can't keep up
+        if let Some(baud_rate) = params.baud_rate {
+            let switched = switch_baud_rate(
+                &mut link,
+                &local,
+                BaudRole::Follower,
+                baud_rate,
+                config.serial_baud,
+                set_baud_rate,
+            )
+            .await;
+
+            match switched {
+                Ok(switched) if switched == baud_rate => {
+                    if log_enabled(LogLevel::Info) {
+                        println!(
+                            "{}",
+                            Colour::Green.paint(format!("USB link running at {} baud", baud_rate))
+                        );
+                    }
+                }
+                Ok(switched) => {
+                    if log_enabled(LogLevel::Warn) {
+                        eprintln!(
+                            "{}",
+                            Colour::Yellow.paint(format!(
+                                "USB link didn't work at {} baud, staying at {} baud",
+                                baud_rate, switched
+                            ))
+                        );
+                    }
+                    baud_rates.retain(|&rate| rate != baud_rate);
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
                     eprintln!(
                         "{} {}",
-                        Colour::Red.paint("Failed to read bytes from serial."),
+                        Colour::Red.paint("Lost the serial connection while switching baud rates."),
                         Colour::White.dimmed().paint("Trying again in 5 seconds...")
                     );
-                    break
-                 };
-
-                // If there are no bytes to read, continue
-                if num_bytes == 0 {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
                     continue;
                 }
+            }
+        }
+
+        // Keep the link running with whatever both ends agreed on, for as long as this connection lasts
+        let (connection, link_rx) =
+            Connection::start(local, params, config.link_config(), stats.clone());
+        connection.configure(link.codec_mut());
+
+        let (mut writer, mut reader) = link.split();
 
-                // Read a packet from the stream
-                let packet = match reader.read_packet() {
-                    Ok(p) => p,
-                    Err(e) => {
-                        eprintln!("{:?}", e);
+        // Set if the DS restarted, and is already waiting for this end to handshake again
+        let restarted = AtomicBool::new(false);
+
+        // Have the DS start a fresh NT session for us, rather than picking up whatever it had before
+        if connection.params().capabilities.contains(Capabilities::OPEN) {
+            connection.send(ProxyPacket::Open(OpenRequest::new(&config.nt_client_name)));
+        }
+
+        // Read packets from usb serial and send them to the nt client
+        let usb_to_nt = async {
+            loop {
+                // Read a packet from the stream, giving up on the DS if it goes quiet for too long
+                let packet = match connection.next_packet(&mut reader).await {
+                    Ok(packet) => packet,
+                    Err(LinkDown::Silent(timeout)) => {
                         eprintln!(
                             "{} {}",
-                            Colour::Red.paint("Failed to read and decode packet from stream."),
+                            Colour::Red.paint(format!(
+                                "DS stopped responding (nothing received for {} ms).",
+                                timeout.as_millis()
+                            )),
+                            Colour::White.dimmed().paint("Trying again in 5 seconds...")
+                        );
+                        break;
+                    }
+                    Err(LinkDown::Failed(e)) => {
+                        eprintln!("{}", e);
+                        eprintln!(
+                            "{} {}",
+                            Colour::Red.paint("Lost the serial connection."),
+                            Colour::White.dimmed().paint("Trying again in 5 seconds...")
+                        );
+                        break;
+                    }
+                    Err(LinkDown::Closed) => {
+                        eprintln!(
+                            "{} {}",
+                            Colour::Red.paint("Serial port was closed."),
                             Colour::White.dimmed().paint("Trying again in 5 seconds...")
                         );
                         break;
                     }
                 };
 
-                // Send the packet to the ws client to be sent over the network
-                tx_to_nt.unbounded_send(packet).unwrap();
+                match connection.receive(packet) {
+                    // Send the packet to the nt client to be sent over the network
+                    // Never wait on the nt client here, or the link would stop being read. Flow
+                    // control keeps the DS from sending more than there's room for, when it's on
+                    Received::Data(packet) => {
+                        tx_to_nt
+                            .unbounded_send((packet, connection.receipt()))
+                            .unwrap();
+                    }
+                    Received::ControlRequest(id, body) => {
+                        let response = match ControlCommand::decode(&body) {
+                            Ok(_) if !config.remote_control => ControlResponse::Failed {
+                                message: String::from("Remote control is turned off"),
+                            },
+                            Ok(command) => {
+                                if log_enabled(LogLevel::Info) {
+                                    println!(
+                                        "{}",
+                                        Colour::White
+                                            .dimmed()
+                                            .paint(format!("Running `{}` for the DS", command))
+                                    );
+                                }
+
+                                run_control_command(
+                                    command,
+                                    &config,
+                                    &connection,
+                                    &stats,
+                                    &reloaded_config,
+                                )
+                            }
+                            // Answer anyway, so the DS isn't left waiting
+                            Err(e) => ControlResponse::Failed {
+                                message: e.to_string(),
+                            },
+                        };
+
+                        connection.send(response.to_packet(id));
+                    }
+                    Received::Clock(estimate) => {
+                        if log_enabled(LogLevel::Debug) {
+                            println!(
+                                "{}",
+                                Colour::White.dimmed().paint(format!(
+                                    "USB link round trip {}us, DS clock {}us ahead",
+                                    estimate.rtt_us, estimate.offset_us
+                                ))
+                            );
+                        }
+                    }
+                    // Only the DS sends commands
+                    Received::ControlResponse(..) => {}
+                    Received::Nothing => {}
+                    // Reconnect straight away, so everything is agreed on again from scratch
+                    Received::Restarted => {
+                        if log_enabled(LogLevel::Warn) {
+                            eprintln!(
+                                "{}",
+                                Colour::Yellow
+                                    .paint("DS restarted its end of the USB link, reconnecting.")
+                            );
+                        }
+                        restarted.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                }
             }
-        });
+        };
 
         let nt_to_usb = async {
-            loop {
-                // Get the next packet from the ws client
-                let ws_packet = rx_from_nt.next().await;
-
-                // If no packet is available, keep looping until one is
-                let Some(packet) = ws_packet else {
-                    continue;
-                };
+            let e = connection
+                .write(&mut writer, link_rx, &mut rx_from_nt, |e| {
+                    if log_enabled(LogLevel::Warn) {
+                        eprintln!(
+                            "{}",
+                            Colour::Yellow.paint(format!("Dropped a packet for the DS: {}", e))
+                        );
+                    }
+                })
+                .await;
 
-                // Write the packet to the stream
-                let Ok(_) = writer.write_packet(packet) else {
-                    eprintln!(
-                        "{} {}",
-                        Colour::Red.paint("Failed to encode and write packet to stream."),
-                        Colour::White.dimmed().paint("Trying again in 5 seconds...")
-                    );
-                    break
-                };
-            }
+            eprintln!("{}", e);
+            eprintln!(
+                "{} {}",
+                Colour::Red.paint("Failed to encode and write packet to stream."),
+                Colour::White.dimmed().paint("Trying again in 5 seconds...")
+            );
         };
 
         // Run both concurrently, and retry on any errors
         pin_mut!(nt_to_usb, usb_to_nt);
         select(nt_to_usb, usb_to_nt).await;
 
-        // Wait the 5 seconds between retry attempts for futures
-        tokio::time::sleep(Duration::from_secs(5)).await;
+        // Leave a record of how the link was doing before it went down
+        stats.record_reconnect();
+        if log_enabled(LogLevel::Info) {
+            eprintln!(
+                "{}",
+                Colour::White
+                    .dimmed()
+                    .paint(format!("USB link stats: {}", stats.snapshot()))
+            );
+        }
+
+        // Wait the 5 seconds between retry attempts for futures, unless the DS is waiting on us
+        if !restarted.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
+    }
+}
+
+/// Carries out a command from the DS, for `connection`
+fn run_control_command(
+    command: ControlCommand,
+    config: &ClientConfig,
+    connection: &Connection,
+    stats: &LinkStats,
+    reloaded_config: &Mutex<Option<ClientConfig>>,
+) -> ControlResponse {
+    let capabilities = connection.params().capabilities;
+
+    match command {
+        // Swapping the config out from under a running connection would leave it half applied
+        ControlCommand::ReloadConfig => match reload_config() {
+            Ok(reloaded) => {
+                *reloaded_config.lock().unwrap() = Some(reloaded);
+                ControlResponse::Done {
+                    message: format!(
+                        "Reloaded `{}`, which takes effect the next time the USB link comes up",
+                        CONFIG_FILE
+                    ),
+                }
+            }
+            Err(message) => ControlResponse::Failed { message },
+        },
+        ControlCommand::RestartNtSession if capabilities.contains(Capabilities::OPEN) => {
+            connection.send(ProxyPacket::Open(OpenRequest::new(&config.nt_client_name)));
+            ControlResponse::Done {
+                message: String::from("Started a fresh NT session"),
+            }
+        }
+        ControlCommand::RestartNtSession => ControlResponse::Failed {
+            message: String::from("This DS can't start fresh NT sessions"),
+        },
+        ControlCommand::ReportStats => ControlResponse::Stats {
+            stats: stats.snapshot(),
+        },
+        ControlCommand::SetLogLevel { level } => {
+            set_log_level(level);
+            ControlResponse::Done {
+                message: format!("Log level set to {}", level),
+            }
+        }
     }
 }
+
+/// Reads the config file again, for the DS
+fn reload_config() -> Result<ClientConfig, String> {
+    let contents = std::fs::read_to_string(CONFIG_FILE)
+        .map_err(|e| format!("Could not read `{}`: {}", CONFIG_FILE, e))?;
+
+    serde_json::from_str(&contents).map_err(|e| format!("Could not parse `{}`: {}", CONFIG_FILE, e))
+}
+
+fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// Returns true if messages at `level` are to be printed
+fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Changes the baud rate of the serial port underneath `link`
+fn set_baud_rate(
+    link: &mut Framed<CaptureTap<SerialStream>, ProxyCodec>,
+    baud_rate: u32,
+) -> io::Result<()> {
+    link.get_mut()
+        .get_mut()
+        .set_baud_rate(baud_rate)
+        .map_err(io::Error::from)
+}
+
+/// Opens a new capture file in the capture folder and starts writing the USB link into it, if there is one
+///
+/// Problems with the capture are only warned about, since the link works fine without it
+fn start_capture(config: &ClientConfig) -> Option<UnboundedSender<CaptureRecord>> {
+    let dir = config.capture_dir.as_ref()?;
+    let path = capture_path(dir, "client", config.capture_format);
+
+    let stopped = path.clone();
+    let capture = spawn_capture(&path, config.capture_format, move |e| {
+        if log_enabled(LogLevel::Warn) {
+            eprintln!(
+                "{}",
+                Colour::Yellow.paint(format!(
+                    "Stopped capturing to `{}`: {}",
+                    stopped.display(),
+                    e
+                ))
+            );
+        }
+    });
+
+    match capture {
+        Ok(tx) => {
+            if log_enabled(LogLevel::Info) {
+                println!(
+                    "{}",
+                    Colour::White
+                        .dimmed()
+                        .paint(format!("Capturing the USB link to `{}`", path.display()))
+                );
+            }
+            Some(tx)
+        }
+        Err(e) => {
+            if log_enabled(LogLevel::Warn) {
+                eprintln!(
+                    "{}",
+                    Colour::Yellow.paint(format!(
+                        "Could not create capture file `{}`: {}",
+                        path.display(),
+                        e
+                    ))
+                );
+            }
+            None
+        }
+    }
+}
+
+/// Keeps the stats file up to date with the USB link stats, if there is one
+async fn write_stats(config: ClientConfig, stats: Arc<LinkStats>) {
+    let Some(path) = config.stats_file.clone() else {
+        return;
+    };
+
+    write_stats_file(stats, path.clone().into(), config.stats_interval(), |e| {
+        if log_enabled(LogLevel::Warn) {
+            eprintln!(
+                "{}",
+                Colour::Yellow.paint(format!("Could not write stats to `{}`: {}", path, e))
+            );
+        }
+    })
+    .await
+}
@@ -0,0 +1,268 @@
+use std::fmt;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::{ProtoError, Result};
+
+/// Bytes of HMAC-SHA1 tag on the end of every authenticated frame payload
+///
+/// Truncated to the shortest length RFC 2104 allows, to keep the overhead down at serial speeds
+pub const AUTH_TAG_LEN: usize = 10;
+
+/// Bytes of frame counter in front of the tag, on every frame sent once the handshake is over
+pub const AUTH_COUNTER_LEN: usize = 4;
+
+/// Packet IDs of `Hello` and `HelloAck`, whose frames are never tied to a session
+const HELLO_PACKET_IDS: [u8; 2] = [3, 4];
+
+/// Signs and checks frame payloads with HMAC-SHA1 (RFC 2104), using a key shared by both ends of the link
+///
+/// Frames from anything that doesn't have the key are rejected. Once the handshake is over, every
+/// frame also carries a counter, and its tag covers the counter and the sessions both ends picked
+/// for the connection in the order they're sent in. Frames recorded off the link (e.g. by something
+/// plugged in between the two ends) are then rejected if they're played back later, into another
+/// connection, or back to the end that sent them.
+///
+/// `Hello` and `HelloAck` frames are the exception, since the other end can still be part way through
+/// its handshake or have restarted its end of the link. The most a recorded one can do is make both
+/// ends start the link over.
+#[derive(Clone)]
+pub struct FrameAuth {
+    /// HMAC state with the key already hashed in
+    mac: Hmac<Sha1>,
+    /// Set once the handshake is over
+    session: Option<AuthSession>,
+}
+
+#[derive(Debug, Clone)]
+struct AuthSession {
+    local: u32,
+    peer: u32,
+    /// Counter on the next frame sent
+    sent: u32,
+    /// Counter on the newest frame accepted, which every frame after it has to be newer than
+    received: Option<u32>,
+}
+
+impl FrameAuth {
+    pub fn new(key: &[u8]) -> Self {
+        FrameAuth {
+            mac: Hmac::new_from_slice(key).expect("HMAC takes keys of any length"),
+            session: None,
+        }
+    }
+
+    /// Ties every frame from now on to the sessions both ends picked in the handshake
+    ///
+    /// Call this as soon as the handshake is over, since the other end starts sending frames that
+    /// can only be checked this way straight after its own
+    pub fn bind(&mut self, local_session: u32, peer_session: u32) {
+        self.session = Some(AuthSession {
+            local: local_session,
+            peer: peer_session,
+            sent: 0,
+            received: None,
+        });
+    }
+
+    /// HMAC state ready for a frame's payload, with the sessions it's sent between hashed in
+    fn mac_between(&self, from: u32, to: u32) -> Hmac<Sha1> {
+        self.mac
+            .clone()
+            .chain_update(from.to_le_bytes())
+            .chain_update(to.to_le_bytes())
+    }
+
+    /// Adds the tag for `payload` on the end of it, and the next frame counter before that once bound
+    pub fn sign(&mut self, payload: &mut BytesMut) {
+        self.sign_from(payload, 0);
+    }
+
+    /// Signs the payload that starts `start` bytes into `dst`, e.g. behind a frame header
+    pub(crate) fn sign_from(&mut self, dst: &mut BytesMut, start: usize) {
+        let mac = match &mut self.session {
+            Some(session) if !is_hello(&dst[start..]) => {
+                // Runs out after 2^32 frames, years at serial speeds. Repeating the last one from then on
+                // just gets everything refused until the link times out and starts over
+                dst.put_u32_le(session.sent);
+                session.sent = session.sent.saturating_add(1);
+
+                let (local, peer) = (session.local, session.peer);
+                self.mac_between(local, peer)
+            }
+            _ => self.mac.clone(),
+        };
+
+        let mac = mac.chain_update(&dst[start..]).finalize().into_bytes();
+        dst.extend_from_slice(&mac[..AUTH_TAG_LEN]);
+    }
+
+    /// Checks the tag on the end of `payload` and strips it off, returning what was signed
+    ///
+    /// Once bound, frames whose counter isn't newer than the last one accepted are refused too
+    pub fn verify(&mut self, mut payload: Bytes) -> Result<Bytes> {
+        let Some(len) = payload.len().checked_sub(AUTH_TAG_LEN) else {
+            return Err(ProtoError::AuthenticationFailed);
+        };
+
+        let bound = !is_hello(&payload);
+        let mac = match &self.session {
+            Some(session) if bound => self.mac_between(session.peer, session.local),
+            _ => self.mac.clone(),
+        };
+
+        // The comparison takes as long wherever the first difference is, so the tag can't be guessed
+        // a byte at a time from how long it takes to be rejected
+        mac.chain_update(&payload[..len])
+            .verify_truncated_left(&payload[len..])
+            .map_err(|_| ProtoError::AuthenticationFailed)?;
+        payload.truncate(len);
+
+        let Some(session) = self.session.as_mut().filter(|_| bound) else {
+            return Ok(payload);
+        };
+        let Some(len) = len.checked_sub(AUTH_COUNTER_LEN) else {
+            return Err(ProtoError::AuthenticationFailed);
+        };
+        let counter = (&payload[len..]).get_u32_le();
+
+        // Frames arrive in the order they were sent, so anything else has been seen before
+        if session.received.is_some_and(|received| counter <= received) {
+            return Err(ProtoError::ReplayedFrame);
+        }
+        session.received = Some(counter);
+
+        payload.truncate(len);
+        Ok(payload)
+    }
+}
+
+/// Returns true for the payload of a `Hello` or `HelloAck` frame
+fn is_hello(payload: &[u8]) -> bool {
+    payload
+        .first()
+        .is_some_and(|id| HELLO_PACKET_IDS.contains(id))
+}
+
+impl fmt::Debug for FrameAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The HMAC state is as good as the key, so keep it out of logs
+        f.write_str("FrameAuth { .. }")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Signs `payload` with `auth`, returning just what was added to it
+    fn tag(auth: &mut FrameAuth, payload: &[u8]) -> Vec<u8> {
+        let mut signed = BytesMut::from(payload);
+        auth.sign(&mut signed);
+        signed[payload.len()..].to_vec()
+    }
+
+    /// Signs `payload` with `auth`, ready to be checked
+    fn signed(auth: &mut FrameAuth, payload: &[u8]) -> Bytes {
+        let mut signed = BytesMut::from(payload);
+        auth.sign(&mut signed);
+        signed.freeze()
+    }
+
+    #[test]
+    fn tags_match_rfc_2202() {
+        let mut auth = FrameAuth::new(&[0x0b; 20]);
+        assert_eq!(
+            tag(&mut auth, b"Hi There"),
+            [0xb6, 0x17, 0x31, 0x86, 0x55, 0x05, 0x72, 0x64, 0xe2, 0x8b]
+        );
+
+        // Keys longer than a block are hashed first
+        let mut auth = FrameAuth::new(&[0xaa; 80]);
+        assert_eq!(
+            tag(
+                &mut auth,
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            ),
+            [0xaa, 0x4a, 0xe5, 0xe1, 0x52, 0x72, 0xd0, 0x0e, 0x95, 0x70]
+        );
+    }
+
+    #[test]
+    fn signed_payloads_round_trip() {
+        let mut auth = FrameAuth::new(b"team1234");
+        let mut payload = BytesMut::from(&b"payload"[..]);
+        auth.sign(&mut payload);
+        assert_eq!(payload.len(), 7 + AUTH_TAG_LEN);
+
+        let payload = payload.freeze();
+        assert_eq!(auth.verify(payload.clone()).unwrap(), &b"payload"[..]);
+
+        // Anything signed with another key, tampered with, or not signed at all is refused
+        let mut other = FrameAuth::new(b"team4321");
+        assert!(matches!(other.verify(payload.clone()), Err(ProtoError::AuthenticationFailed)));
+
+        let mut tampered = payload.to_vec();
+        tampered[0] ^= 1;
+        assert!(auth.verify(Bytes::from(tampered)).is_err());
+        assert!(auth.verify(Bytes::from_static(b"short")).is_err());
+    }
+
+    #[test]
+    fn bound_frames_are_only_accepted_once() {
+        let mut console = FrameAuth::new(b"team1234");
+        let mut client = FrameAuth::new(b"team1234");
+        console.bind(1, 2);
+        client.bind(2, 1);
+
+        let frames: Vec<_> = (0..3).map(|_| signed(&mut console, b"payload")).collect();
+        assert_eq!(frames[0].len(), 7 + AUTH_COUNTER_LEN + AUTH_TAG_LEN);
+
+        assert_eq!(client.verify(frames[0].clone()).unwrap(), &b"payload"[..]);
+        assert!(matches!(
+            client.verify(frames[0].clone()),
+            Err(ProtoError::ReplayedFrame)
+        ));
+
+        // Lost frames are skipped over, but can't turn up again afterwards
+        assert!(client.verify(frames[2].clone()).is_ok());
+        assert!(matches!(
+            client.verify(frames[1].clone()),
+            Err(ProtoError::ReplayedFrame)
+        ));
+
+        // Nor can frames be sent back to the end they came from, or into another connection
+        assert!(matches!(
+            console.verify(frames[1].clone()),
+            Err(ProtoError::AuthenticationFailed)
+        ));
+        let mut reconnected = FrameAuth::new(b"team1234");
+        reconnected.bind(3, 1);
+        assert!(matches!(
+            reconnected.verify(frames[1].clone()),
+            Err(ProtoError::AuthenticationFailed)
+        ));
+    }
+
+    #[test]
+    fn hellos_are_never_bound() {
+        let mut console = FrameAuth::new(b"team1234");
+        let mut client = FrameAuth::new(b"team1234");
+        console.bind(1, 2);
+
+        // The client is still part way through its handshake
+        let hello = signed(&mut console, &[HELLO_PACKET_IDS[0], 1, 2, 3]);
+        assert_eq!(hello.len(), 4 + AUTH_TAG_LEN);
+        assert_eq!(client.verify(hello.clone()).unwrap(), &[3, 1, 2, 3][..]);
+
+        client.bind(2, 1);
+        assert!(client.verify(hello).is_ok());
+    }
+
+    #[test]
+    fn debug_hides_the_key() {
+        assert_eq!(format!("{:?}", FrameAuth::new(b"secret")), "FrameAuth { .. }");
+    }
+}
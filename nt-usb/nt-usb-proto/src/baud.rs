@@ -0,0 +1,289 @@
+use std::io;
+use std::time::Duration;
+
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use tokio::time::Instant;
+
+use crate::{
+    perform_handshake, LinkInfo, LinkStats, ProtoError, ProxyPacket, Result, HELLO_INTERVAL,
+};
+
+/// Baud rate both ends open the port at, and fall back to if a faster one doesn't work out
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// Baud rates advertised unless configured otherwise, which most USB serial adapters can manage
+pub const DEFAULT_BAUD_RATES: [u32; 4] = [115_200, 230_400, 460_800, 921_600];
+
+/// How long to leave the `SwitchBaudAck` to drain out of the port before switching under it
+pub const BAUD_SWITCH_SETTLE: Duration = Duration::from_millis(100);
+
+/// How long each step of a baud rate switch may take before both ends give up and fall back
+pub const BAUD_SWITCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Which end of the link runs a baud rate switch
+///
+/// Exactly one end has to lead, or both could switch at once and lose each other's answers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BaudRole {
+    /// Sends the `SwitchBaud`, and confirms the switch once it's been answered at the new rate
+    Leader,
+    /// Answers the `SwitchBaud`, and keeps the new rate once the leader confirms it
+    Follower,
+}
+
+/// Moves a freshly handshaken link from `fallback` up to `baud_rate`, returning the rate it ends up at
+///
+/// Both ends agree to switch with a `SwitchBaud`/`SwitchBaudAck` exchange, then check the new rate
+/// works by running the exchange again at it. The leader only keeps the new rate once it's been
+/// answered there, and the follower only once the leader has confirmed that with a `SwitchBaudAck` of
+/// its own. If either step doesn't finish within [`BAUD_SWITCH_TIMEOUT`], both ends go back to
+/// `fallback` and handshake there instead. Only errors that mean the link is gone are returned.
+///
+/// The follower keeps answering at the new rate until the confirmation arrives, and the leader's
+/// [`Connection`](crate::Connection) confirms again each time, so a lost confirmation only costs a
+/// resend. If every one of them is lost the ends end up at different rates, which the heartbeat
+/// timeout notices and reconnects from scratch.
+///
+/// `set_baud_rate` changes the rate of the port underneath `link`.
+pub async fn switch_baud_rate<S, F>(
+    link: &mut S,
+    local: &LinkInfo,
+    role: BaudRole,
+    baud_rate: u32,
+    fallback: u32,
+    mut set_baud_rate: F,
+) -> Result<u32>
+where
+    S: Stream<Item = Result<ProxyPacket>> + Sink<ProxyPacket, Error = ProtoError> + Unpin,
+    F: FnMut(&mut S, u32) -> io::Result<()>,
+{
+    let agreed = match role {
+        BaudRole::Leader => lead_switch(link, baud_rate).await?,
+        BaudRole::Follower => follow_switch(link, baud_rate).await?,
+    };
+
+    if agreed {
+        set_baud_rate(link, baud_rate)?;
+
+        // Going through the exchange again proves frames make it across in both directions at the new rate
+        let confirmed = match role {
+            BaudRole::Leader => lead_confirm(link, baud_rate).await?,
+            BaudRole::Follower => follow_confirm(link, baud_rate).await?,
+        };
+        if confirmed {
+            return Ok(baud_rate);
+        }
+
+        set_baud_rate(link, fallback)?;
+    }
+
+    // Meet the other end back at the rate that's known to work, where the key has already been checked
+    perform_handshake(link, local, &LinkStats::default()).await?;
+    Ok(fallback)
+}
+
+/// Sends `SwitchBaud` until it's answered, returning false if it never is
+async fn lead_switch<S>(link: &mut S, baud_rate: u32) -> Result<bool>
+where
+    S: Stream<Item = Result<ProxyPacket>> + Sink<ProxyPacket, Error = ProtoError> + Unpin,
+{
+    let deadline = Instant::now() + BAUD_SWITCH_TIMEOUT;
+
+    while Instant::now() < deadline {
+        link.send(ProxyPacket::SwitchBaud(baud_rate)).await?;
+
+        let resend = (Instant::now() + HELLO_INTERVAL).min(deadline);
+        loop {
+            match tokio::time::timeout_at(resend, link.next()).await {
+                Ok(Some(Ok(ProxyPacket::SwitchBaudAck(acked)))) if acked == baud_rate => {
+                    return Ok(true)
+                }
+                // Anything else is left over from the handshake
+                Ok(Some(Ok(_))) => continue,
+                Ok(Some(Err(e))) => return Err(e),
+                Ok(None) => return Err(closed()),
+                Err(_) => break,
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// Waits for the leader's `SwitchBaud` and answers it, returning false if it never arrives
+async fn follow_switch<S>(link: &mut S, baud_rate: u32) -> Result<bool>
+where
+    S: Stream<Item = Result<ProxyPacket>> + Sink<ProxyPacket, Error = ProtoError> + Unpin,
+{
+    // Give the leader a little longer than it gives itself, so it always gives up first
+    let deadline = Instant::now() + BAUD_SWITCH_TIMEOUT + HELLO_INTERVAL;
+
+    loop {
+        match tokio::time::timeout_at(deadline, link.next()).await {
+            Ok(Some(Ok(ProxyPacket::SwitchBaud(requested)))) if requested == baud_rate => break,
+            // Anything else is left over from the handshake
+            Ok(Some(Ok(_))) => continue,
+            Ok(Some(Err(e))) => return Err(e),
+            Ok(None) => return Err(closed()),
+            Err(_) => return Ok(false),
+        }
+    }
+
+    link.send(ProxyPacket::SwitchBaudAck(baud_rate)).await?;
+
+    // The answer has to go out at the old rate, so let it finish before switching
+    tokio::time::sleep(BAUD_SWITCH_SETTLE).await;
+    Ok(true)
+}
+
+/// Runs the leader's side of the exchange again at the new rate, then confirms it
+async fn lead_confirm<S>(link: &mut S, baud_rate: u32) -> Result<bool>
+where
+    S: Stream<Item = Result<ProxyPacket>> + Sink<ProxyPacket, Error = ProtoError> + Unpin,
+{
+    if !lead_switch(link, baud_rate).await? {
+        return Ok(false);
+    }
+
+    link.send(ProxyPacket::SwitchBaudAck(baud_rate)).await?;
+    Ok(true)
+}
+
+/// Answers the leader's `SwitchBaud` at the new rate until the leader confirms it, returning false if
+/// it never does
+async fn follow_confirm<S>(link: &mut S, baud_rate: u32) -> Result<bool>
+where
+    S: Stream<Item = Result<ProxyPacket>> + Sink<ProxyPacket, Error = ProtoError> + Unpin,
+{
+    // Give the leader a little longer than it gives itself, so it always gives up first
+    let deadline = Instant::now() + BAUD_SWITCH_TIMEOUT + HELLO_INTERVAL;
+    let mut answered = false;
+
+    loop {
+        // Once we've answered, keep answering in case the leader's confirmation got lost
+        let wait = match answered {
+            true => (Instant::now() + HELLO_INTERVAL).min(deadline),
+            false => deadline,
+        };
+
+        match tokio::time::timeout_at(wait, link.next()).await {
+            Ok(Some(Ok(ProxyPacket::SwitchBaud(requested)))) if requested == baud_rate => {
+                link.send(ProxyPacket::SwitchBaudAck(baud_rate)).await?;
+                answered = true;
+            }
+            Ok(Some(Ok(ProxyPacket::SwitchBaudAck(confirmed)))) if confirmed == baud_rate => {
+                return Ok(true)
+            }
+            // Anything else is left over from before the switch
+            Ok(Some(Ok(_))) => continue,
+            Ok(Some(Err(e))) => return Err(e),
+            Ok(None) => return Err(closed()),
+            Err(_) if Instant::now() >= deadline => return Ok(false),
+            Err(_) => link.send(ProxyPacket::SwitchBaudAck(baud_rate)).await?,
+        }
+    }
+}
+
+fn closed() -> ProtoError {
+    ProtoError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "USB link closed while switching baud rates",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::handshake::tests::{link_pair, TestLink};
+    use crate::Capabilities;
+
+    const FAST: u32 = 921_600;
+
+    /// Lets `lose` drop packets from `from` by rate, on top of packets sent at a rate the other end's
+    /// port isn't at never arriving
+    fn connect(
+        from: &mut TestLink,
+        rate: &Arc<AtomicU32>,
+        other: &Arc<AtomicU32>,
+        mut lose: impl FnMut(u32, &ProxyPacket) -> bool + Send + 'static,
+    ) {
+        let (rate, other) = (rate.clone(), other.clone());
+        from.deliver = Box::new(move |packet| {
+            let rate = rate.load(Ordering::SeqCst);
+            rate == other.load(Ordering::SeqCst) && !lose(rate, packet)
+        });
+    }
+
+    async fn switch(
+        lose_from_leader: impl FnMut(u32, &ProxyPacket) -> bool + Send + 'static,
+        lose_from_follower: impl FnMut(u32, &ProxyPacket) -> bool + Send + 'static,
+    ) -> (u32, u32) {
+        let (mut leader, mut follower) = link_pair();
+        let leader_rate = Arc::new(AtomicU32::new(DEFAULT_BAUD_RATE));
+        let follower_rate = Arc::new(AtomicU32::new(DEFAULT_BAUD_RATE));
+        connect(&mut leader, &leader_rate, &follower_rate, lose_from_leader);
+        connect(&mut follower, &follower_rate, &leader_rate, lose_from_follower);
+
+        let local = LinkInfo::new("test 1.0", Capabilities::BAUD_SWITCH, 1024);
+        let set = |rate: Arc<AtomicU32>| {
+            move |_: &mut TestLink, baud_rate| {
+                rate.store(baud_rate, Ordering::SeqCst);
+                Ok(())
+            }
+        };
+
+        let (leader_result, follower_result) = tokio::join!(
+            switch_baud_rate(
+                &mut leader,
+                &local,
+                BaudRole::Leader,
+                FAST,
+                DEFAULT_BAUD_RATE,
+                set(leader_rate.clone())
+            ),
+            switch_baud_rate(
+                &mut follower,
+                &local,
+                BaudRole::Follower,
+                FAST,
+                DEFAULT_BAUD_RATE,
+                set(follower_rate.clone())
+            ),
+        );
+
+        let (leader_result, follower_result) = (leader_result.unwrap(), follower_result.unwrap());
+        assert_eq!(leader_result, leader_rate.load(Ordering::SeqCst));
+        assert_eq!(follower_result, follower_rate.load(Ordering::SeqCst));
+        (leader_result, follower_result)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn both_ends_switch() {
+        assert_eq!(switch(|_, _| false, |_, _| false).await, (FAST, FAST));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn both_ends_fall_back_if_the_follower_is_never_heard_at_the_new_rate() {
+        let results = switch(|_, _| false, |rate, _| rate == FAST).await;
+        assert_eq!(results, (DEFAULT_BAUD_RATE, DEFAULT_BAUD_RATE));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn both_ends_fall_back_if_the_leader_is_never_heard_at_the_new_rate() {
+        let results = switch(|rate, _| rate == FAST, |_, _| false).await;
+        assert_eq!(results, (DEFAULT_BAUD_RATE, DEFAULT_BAUD_RATE));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn a_lost_answer_at_the_new_rate_is_resent() {
+        let mut lost = false;
+        let lose_first_answer = move |rate, packet: &ProxyPacket| {
+            let answer = rate == FAST && matches!(packet, ProxyPacket::SwitchBaudAck(_));
+            answer && !std::mem::replace(&mut lost, true)
+        };
+        assert_eq!(switch(|_, _| false, lose_first_answer).await, (FAST, FAST));
+    }
+}
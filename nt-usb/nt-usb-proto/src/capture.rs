@@ -0,0 +1,492 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use futures::channel::mpsc::UnboundedSender;
+use futures::{FutureExt, StreamExt};
+use serde::Deserialize;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Marks the start of a capture file in the native format
+pub const CAPTURE_MAGIC: [u8; 8] = *b"NTUSBCAP";
+
+/// Version of the native capture format written by this build
+pub const CAPTURE_VERSION: u8 = 1;
+
+/// Link type of the interface in pcapng captures (`LINKTYPE_USER0`)
+///
+/// Point Wireshark's `DLT_USER` table at a dissector to decode the frames, otherwise they show up as
+/// raw bytes
+pub const PCAPNG_LINK_TYPE: u16 = 147;
+
+/// Bytes before the data of a record in the native format: timestamp, direction and length
+const RECORD_HEADER_LEN: usize = 13;
+
+const PCAPNG_SECTION_HEADER: u32 = 0x0A0D_0D0A;
+const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+const PCAPNG_INTERFACE_DESCRIPTION: u32 = 1;
+const PCAPNG_ENHANCED_PACKET: u32 = 6;
+const PCAPNG_OPT_END: u16 = 0;
+const PCAPNG_OPT_EPB_FLAGS: u16 = 2;
+
+/// Which way bytes crossed the link, as seen from the end that captured them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// One chunk of bytes written to or read from the link, exactly as it crossed it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    /// When the bytes were written or read, in microseconds since the Unix epoch
+    pub timestamp_us: u64,
+    pub direction: Direction,
+    pub data: Bytes,
+}
+
+impl CaptureRecord {
+    /// Records `data` as crossing the link right now
+    pub fn now(direction: Direction, data: Bytes) -> Self {
+        CaptureRecord {
+            timestamp_us: now_us(),
+            direction,
+            data,
+        }
+    }
+}
+
+/// File format to write captures in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureFormat {
+    /// A header, then each record as a little endian timestamp, direction and length, followed by the data
+    #[default]
+    Native,
+    /// pcapng, with one interface for the link and inbound/outbound flags on every packet, which
+    /// Wireshark can open
+    Pcapng,
+}
+
+impl CaptureFormat {
+    /// File extension captures in this format are usually saved with
+    pub fn extension(self) -> &'static str {
+        match self {
+            CaptureFormat::Native => "ntcap",
+            CaptureFormat::Pcapng => "pcapng",
+        }
+    }
+}
+
+/// Writes [`CaptureRecord`]s to a capture file
+///
+/// Native captures can be turned into pcapng afterwards by reading them with a [`CaptureReader`] and
+/// writing every record to a pcapng `CaptureWriter`
+#[derive(Debug)]
+pub struct CaptureWriter<W: Write> {
+    writer: W,
+    format: CaptureFormat,
+}
+
+impl<W: Write> CaptureWriter<W> {
+    /// Starts a new capture, writing the file header straight away
+    pub fn new(mut writer: W, format: CaptureFormat) -> io::Result<Self> {
+        match format {
+            CaptureFormat::Native => {
+                writer.write_all(&CAPTURE_MAGIC)?;
+                writer.write_all(&[CAPTURE_VERSION])?;
+            }
+            CaptureFormat::Pcapng => {
+                // Section header: byte order magic, version 1.0 and an unknown section length
+                let mut block = Vec::with_capacity(28);
+                put_pcapng_header(&mut block, PCAPNG_SECTION_HEADER, 28);
+                block.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+                block.extend_from_slice(&1u16.to_le_bytes());
+                block.extend_from_slice(&0u16.to_le_bytes());
+                block.extend_from_slice(&(-1i64).to_le_bytes());
+                block.extend_from_slice(&28u32.to_le_bytes());
+
+                // The one interface every packet is on, with microsecond timestamps and no snap length
+                put_pcapng_header(&mut block, PCAPNG_INTERFACE_DESCRIPTION, 20);
+                block.extend_from_slice(&PCAPNG_LINK_TYPE.to_le_bytes());
+                block.extend_from_slice(&0u16.to_le_bytes());
+                block.extend_from_slice(&0u32.to_le_bytes());
+                block.extend_from_slice(&20u32.to_le_bytes());
+
+                writer.write_all(&block)?;
+            }
+        }
+
+        Ok(CaptureWriter { writer, format })
+    }
+
+    pub fn write_record(&mut self, record: &CaptureRecord) -> io::Result<()> {
+        let len = u32::try_from(record.data.len())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Capture record is too long"))?;
+
+        match self.format {
+            CaptureFormat::Native => {
+                let mut header = [0u8; RECORD_HEADER_LEN];
+                header[..8].copy_from_slice(&record.timestamp_us.to_le_bytes());
+                header[8] = match record.direction {
+                    Direction::Sent => 0,
+                    Direction::Received => 1,
+                };
+                header[9..].copy_from_slice(&len.to_le_bytes());
+
+                self.writer.write_all(&header)?;
+                self.writer.write_all(&record.data)?;
+            }
+            CaptureFormat::Pcapng => {
+                // Enhanced packet block, with the data padded to 32 bits and an `epb_flags` option
+                let padding = record.data.len().next_multiple_of(4) - record.data.len();
+                let block_len = 44 + len + padding as u32;
+                let flags: u32 = match record.direction {
+                    Direction::Received => 1,
+                    Direction::Sent => 2,
+                };
+
+                let mut header = Vec::with_capacity(28);
+                put_pcapng_header(&mut header, PCAPNG_ENHANCED_PACKET, block_len);
+                header.extend_from_slice(&0u32.to_le_bytes());
+                header.extend_from_slice(&((record.timestamp_us >> 32) as u32).to_le_bytes());
+                header.extend_from_slice(&(record.timestamp_us as u32).to_le_bytes());
+                header.extend_from_slice(&len.to_le_bytes());
+                header.extend_from_slice(&len.to_le_bytes());
+
+                let mut trailer = Vec::with_capacity(padding + 16);
+                trailer.resize(padding, 0);
+                trailer.extend_from_slice(&PCAPNG_OPT_EPB_FLAGS.to_le_bytes());
+                trailer.extend_from_slice(&4u16.to_le_bytes());
+                trailer.extend_from_slice(&flags.to_le_bytes());
+                trailer.extend_from_slice(&PCAPNG_OPT_END.to_le_bytes());
+                trailer.extend_from_slice(&0u16.to_le_bytes());
+                trailer.extend_from_slice(&block_len.to_le_bytes());
+
+                self.writer.write_all(&header)?;
+                self.writer.write_all(&record.data)?;
+                self.writer.write_all(&trailer)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads [`CaptureRecord`]s back out of a capture file in the native format
+#[derive(Debug)]
+pub struct CaptureReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> CaptureReader<R> {
+    /// Checks the file header, failing with `InvalidData` if this isn't a capture this build can read
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; CAPTURE_MAGIC.len() + 1];
+        reader.read_exact(&mut header)?;
+
+        if header[..CAPTURE_MAGIC.len()] != CAPTURE_MAGIC {
+            return Err(io::Error::new(ErrorKind::InvalidData, "Not a USB link capture"));
+        }
+
+        let version = header[CAPTURE_MAGIC.len()];
+        if version != CAPTURE_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Unsupported capture version {}", version),
+            ));
+        }
+
+        Ok(CaptureReader { reader })
+    }
+
+    /// Reads the next record, or `None` at the end of the capture
+    ///
+    /// A capture cut off part way through a record (e.g. by a crash) ends at the last whole one
+    pub fn read_record(&mut self) -> io::Result<Option<CaptureRecord>> {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        match self.reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let timestamp_us = u64::from_le_bytes(header[..8].try_into().unwrap());
+        let direction = match header[8] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            direction => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown capture direction {}", direction),
+                ))
+            }
+        };
+        let len = u32::from_le_bytes(header[9..].try_into().unwrap()) as usize;
+
+        let mut data = vec![0u8; len];
+        match self.reader.read_exact(&mut data) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        Ok(Some(CaptureRecord {
+            timestamp_us,
+            direction,
+            data: data.into(),
+        }))
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R: Read> Iterator for CaptureReader<R> {
+    type Item = io::Result<CaptureRecord>;
+
+    fn next(&mut self) -> Option<io::Result<CaptureRecord>> {
+        self.read_record().transpose()
+    }
+}
+
+/// Passes reads and writes through to a serial port, sending a copy of every byte to a capture
+///
+/// Sits underneath the [`ProxyCodec`](crate::ProxyCodec), so captures have exactly what crossed the
+/// link, corrupted frames and garbage between frames included
+#[derive(Debug)]
+pub struct CaptureTap<S> {
+    inner: S,
+    records: Option<UnboundedSender<CaptureRecord>>,
+}
+
+impl<S> CaptureTap<S> {
+    /// Passes everything through without capturing it, until [`with_sender`](Self::with_sender) is used
+    pub fn new(inner: S) -> Self {
+        CaptureTap {
+            inner,
+            records: None,
+        }
+    }
+
+    /// Sends a record of every read and write to `records`, e.g. for a task writing them to a file
+    ///
+    /// Capturing stops quietly if the receiving end goes away
+    pub fn with_sender(mut self, records: UnboundedSender<CaptureRecord>) -> Self {
+        self.records = Some(records);
+        self
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn record(&self, direction: Direction, data: &[u8]) {
+        if let Some(records) = &self.records {
+            if !data.is_empty() {
+                let record = CaptureRecord::now(direction, Bytes::copy_from_slice(data));
+                let _ = records.unbounded_send(record);
+            }
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for CaptureTap<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = poll {
+            self.record(Direction::Received, &buf.filled()[start..]);
+        }
+
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for CaptureTap<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(written)) = poll {
+            self.record(Direction::Sent, &buf[..written]);
+        }
+
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Path for a new capture file in `dir`, named after `program` and when it was started
+pub fn capture_path(dir: impl AsRef<Path>, program: &str, format: CaptureFormat) -> PathBuf {
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs());
+
+    dir.as_ref()
+        .join(format!("{}-{}.{}", program, started, format.extension()))
+}
+
+/// Creates a capture file at `path` and writes the records sent to the returned sender into it, on a
+/// thread of its own
+///
+/// Records are only flushed once there's a lull, so a crash loses as little as possible. If writing
+/// fails later on, capturing stops and `on_error` is told why.
+pub fn spawn_capture(
+    path: &Path,
+    format: CaptureFormat,
+    on_error: impl FnOnce(io::Error) + Send + 'static,
+) -> io::Result<UnboundedSender<CaptureRecord>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut writer = CaptureWriter::new(BufWriter::new(File::create(path)?), format)?;
+
+    let (tx, mut rx) = futures::channel::mpsc::unbounded();
+    thread::spawn(move || {
+        while let Some(record) = futures::executor::block_on(rx.next()) {
+            let mut result = writer.write_record(&record);
+            while result.is_ok() {
+                match rx.next().now_or_never() {
+                    Some(Some(record)) => result = writer.write_record(&record),
+                    _ => break,
+                }
+            }
+
+            if let Err(e) = result.and_then(|_| writer.flush()) {
+                on_error(e);
+                break;
+            }
+        }
+    });
+
+    Ok(tx)
+}
+
+/// Starts a pcapng block of `block_type`, which is `len` bytes long all told
+fn put_pcapng_header(block: &mut Vec<u8>, block_type: u32, len: u32) {
+    block.extend_from_slice(&block_type.to_le_bytes());
+    block.extend_from_slice(&len.to_le_bytes());
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_micros() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records() -> Vec<CaptureRecord> {
+        vec![
+            CaptureRecord {
+                timestamp_us: 123,
+                direction: Direction::Sent,
+                data: Bytes::from_static(b"abc"),
+            },
+            CaptureRecord {
+                timestamp_us: (5 << 32) | 7,
+                direction: Direction::Received,
+                data: Bytes::from_static(b"\0\x01"),
+            },
+        ]
+    }
+
+    #[test]
+    fn native_captures_round_trip() {
+        let mut writer = CaptureWriter::new(Vec::new(), CaptureFormat::Native).unwrap();
+        for record in records() {
+            writer.write_record(&record).unwrap();
+        }
+
+        // A capture cut off part way through a record still reads up to there
+        let mut capture = writer.into_inner();
+        capture.extend_from_slice(&[1, 2, 3]);
+
+        let read = CaptureReader::new(&capture[..])
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(read, records());
+
+        assert!(CaptureReader::new(&b"NOTACAPTURE"[..]).is_err());
+    }
+
+    #[test]
+    fn pcapng_captures_are_well_formed() {
+        let mut writer = CaptureWriter::new(Vec::new(), CaptureFormat::Pcapng).unwrap();
+        for record in records() {
+            writer.write_record(&record).unwrap();
+        }
+        let capture = writer.into_inner();
+        let u32_at = |offset: usize| u32::from_le_bytes(capture[offset..offset + 4].try_into().unwrap());
+
+        // Every block is padded to 32 bits, with its length at both ends
+        let mut blocks = Vec::new();
+        let mut offset = 0;
+        while offset < capture.len() {
+            let len = u32_at(offset + 4) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(u32_at(offset + len - 4) as usize, len);
+
+            blocks.push((u32_at(offset), offset));
+            offset += len;
+        }
+        assert_eq!(offset, capture.len());
+
+        // Section header, interface description, then one enhanced packet block per record
+        let types: Vec<_> = blocks.iter().map(|(block_type, _)| *block_type).collect();
+        assert_eq!(types, [0x0A0D_0D0A, 1, 6, 6]);
+
+        let (_, packet) = blocks[3];
+        assert_eq!(u32_at(packet + 12), 5);
+        assert_eq!(u32_at(packet + 16), 7);
+        assert_eq!(u32_at(packet + 20), 2);
+        assert_eq!(&capture[packet + 28..packet + 30], b"\0\x01");
+    }
+
+    #[test]
+    fn reader_refuses_pcapng() {
+        let writer = CaptureWriter::new(Vec::new(), CaptureFormat::Pcapng).unwrap();
+        assert!(CaptureReader::new(&writer.into_inner()[..]).is_err());
+    }
+}
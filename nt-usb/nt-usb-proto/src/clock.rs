@@ -0,0 +1,157 @@
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::ProxyPacket;
+
+/// How often each end asks the other for the time once timestamps have been negotiated
+pub const CLOCK_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many of the latest round trips the clock offset is picked from
+pub const CLOCK_SYNC_SAMPLES: usize = 8;
+
+/// Microseconds on this end's monotonic clock, which starts the first time it's read
+///
+/// The two ends' clocks start at different times, so their readings are only comparable after
+/// correcting for the offset a [`ClockSync`] works out
+pub fn monotonic_us() -> u64 {
+    static START: OnceLock<Instant> = OnceLock::new();
+
+    START.get_or_init(Instant::now).elapsed().as_micros() as u64
+}
+
+/// How the other end's clock relates to this one, worked out from a round trip
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockEstimate {
+    /// How long the round trip took, in microseconds
+    pub rtt_us: u64,
+    /// How far ahead of this end's clock the other end's is, in microseconds
+    pub offset_us: i64,
+}
+
+impl ClockEstimate {
+    /// Half the round trip, as a guess at how long one way takes when there's nothing better
+    pub fn one_way_us(&self) -> u64 {
+        self.rtt_us / 2
+    }
+
+    /// How long a frame the other end stamped at `sent_us` on its clock took to get here
+    ///
+    /// This is only as good as the offset, so it comes out slightly negative now and then and is
+    /// clamped to 0
+    pub fn latency_us(&self, sent_us: u64) -> u64 {
+        let sent_here = sent_us as i64 - self.offset_us;
+        (monotonic_us() as i64 - sent_here).max(0) as u64
+    }
+}
+
+/// Works out the other end's clock offset from `TimeRequest`/`TimeReply` round trips, NTP style
+///
+/// Round trips that got held up on the way are lopsided and throw the offset off, so the estimate
+/// comes from the quickest of the last [`CLOCK_SYNC_SAMPLES`] rather than the latest.
+#[derive(Debug, Clone, Default)]
+pub struct ClockSync {
+    samples: VecDeque<ClockEstimate>,
+}
+
+impl ClockSync {
+    pub fn new() -> Self {
+        ClockSync::default()
+    }
+
+    /// Makes a `TimeRequest` stamped with the time right now
+    pub fn request(&self) -> ProxyPacket {
+        ProxyPacket::TimeRequest(monotonic_us())
+    }
+
+    /// Answers a `TimeRequest` from the other end with the time right now
+    pub fn reply(&self, origin_us: u64) -> ProxyPacket {
+        ProxyPacket::TimeReply(origin_us, monotonic_us())
+    }
+
+    /// Adds the round trip a `TimeReply` finished, returning the best estimate so far
+    pub fn on_reply(&mut self, origin_us: u64, remote_us: u64) -> ClockEstimate {
+        self.on_reply_at(origin_us, remote_us, monotonic_us())
+    }
+
+    /// Adds the round trip a `TimeReply` finished when this end's clock read `now_us`
+    pub fn on_reply_at(&mut self, origin_us: u64, remote_us: u64, now_us: u64) -> ClockEstimate {
+        let rtt_us = now_us.saturating_sub(origin_us);
+
+        // The other end read its clock about halfway through the round trip
+        let midpoint = origin_us + rtt_us / 2;
+        let offset_us = remote_us as i64 - midpoint as i64;
+
+        if self.samples.len() == CLOCK_SYNC_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(ClockEstimate { rtt_us, offset_us });
+
+        self.estimate().unwrap()
+    }
+
+    /// The estimate from the quickest recent round trip, if there's been one
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        self.samples
+            .iter()
+            .min_by_key(|sample| sample.rtt_us)
+            .copied()
+    }
+
+    /// Forgets every round trip, for when the other end has restarted and its clock with it
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_is_estimated_from_round_trips() {
+        let mut sync = ClockSync::new();
+        assert!(sync.estimate().is_none());
+
+        // The other end's clock is a second ahead, and answered half way through the round trip
+        let estimate = sync.on_reply_at(10_000, 1_011_000, 12_000);
+        assert_eq!(
+            estimate,
+            ClockEstimate {
+                rtt_us: 2000,
+                offset_us: 1_000_000,
+            }
+        );
+        assert_eq!(sync.estimate(), Some(estimate));
+
+        // A round trip held up on the way back is lopsided, so the quicker one is still used
+        sync.on_reply_at(20_000, 1_020_500, 30_000);
+        assert_eq!(sync.estimate(), Some(estimate));
+
+        sync.reset();
+        assert!(sync.estimate().is_none());
+    }
+
+    #[test]
+    fn replies_carry_the_request_back() {
+        let sync = ClockSync::new();
+        let ProxyPacket::TimeReply(origin, remote) = sync.reply(42) else {
+            panic!("no reply");
+        };
+        assert_eq!(origin, 42);
+        assert!(remote <= monotonic_us());
+    }
+
+    #[test]
+    fn latency_allows_for_the_offset() {
+        let estimate = ClockEstimate {
+            rtt_us: 200,
+            offset_us: 1_000_000,
+        };
+        assert_eq!(estimate.one_way_us(), 100);
+
+        // Stamped by the other end's clock 500 us ago
+        let sent_us = monotonic_us() + 1_000_000 - 500;
+        assert!((500..1500).contains(&estimate.latency_us(sent_us)));
+    }
+}
@@ -0,0 +1,268 @@
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio::time::Instant;
+
+use crate::{ProtoError, ProxyPacket, Result};
+
+/// Packet ID of `Batch` packets, for reporting truncated ones
+const BATCH_PACKET_ID: u8 = 22;
+
+/// Bytes in front of each packet in a `Batch`, giving its length
+pub const BATCH_HEADER_LEN: usize = 2;
+
+/// Longest a small packet is held back waiting for others to share its frame, unless configured otherwise
+pub const DEFAULT_COALESCE_WINDOW: Duration = Duration::from_millis(1);
+
+/// Largest encoded packet worth holding back to share a frame, in bytes
+///
+/// Anything bigger is already long enough that the frame around it hardly matters
+pub const MAX_COALESCED_PACKET_LEN: usize = 256;
+
+/// Largest `Batch` put together unless the other end accepts less, in bytes
+///
+/// About 90 ms on the wire at 115200 baud, so the packets at the front don't wait too long on the ones
+/// at the back
+pub const MAX_BATCH_LEN: usize = 1024;
+
+/// Holds small packets back for a moment, so several of them can go out in one `Batch` frame
+///
+/// NT4 value updates are only a few bytes each, so without this the frame header, checksum and write
+/// around each one cost more than the update itself. Only use this once the other end has advertised
+/// `Capabilities::BATCHING`.
+#[derive(Debug)]
+pub struct Coalescer {
+    window: Duration,
+    max_len: usize,
+    /// Packets held back, in the order they're to be sent
+    packets: Vec<ProxyPacket>,
+    /// The same packets, already encoded the way a `Batch` carries them
+    batch: BytesMut,
+    /// When the oldest packet held back has to go out, with or without company
+    deadline: Option<Instant>,
+}
+
+impl Coalescer {
+    /// Holds packets back for up to `window`, in batches of up to `max_len` bytes
+    pub fn new(window: Duration, max_len: usize) -> Self {
+        Coalescer {
+            window,
+            max_len,
+            packets: Vec::new(),
+            batch: BytesMut::new(),
+            deadline: None,
+        }
+    }
+
+    /// Takes a packet on its way to the link, returning whatever has to be written now, in order
+    ///
+    /// Small packets are held back until the batch is full or the oldest has waited long enough.
+    /// Anything else goes straight out, behind whatever was held back so the order is kept.
+    pub fn push(&mut self, packet: ProxyPacket) -> Coalesced {
+        if !can_coalesce(&packet) {
+            return Coalesced(self.flush(), Some(packet));
+        }
+
+        // Encode the packet into the batch straight away, to find out whether it fits
+        let start = self.batch.len();
+        self.batch.put_u16_le(0);
+        let encoded = packet.encode(&mut self.batch);
+
+        // Anything that won't encode goes straight through, to be refused where it's written
+        let len = self.batch.len() - start - BATCH_HEADER_LEN;
+        if encoded.is_err() || len > MAX_COALESCED_PACKET_LEN || len > u16::MAX as usize {
+            self.batch.truncate(start);
+            return Coalesced(self.flush(), Some(packet));
+        }
+        self.batch[start..start + BATCH_HEADER_LEN].copy_from_slice(&(len as u16).to_le_bytes());
+
+        // Send off what's already held back if this one doesn't fit in with it, and start again
+        let mut full = None;
+        if self.batch.len() > self.max_len && !self.packets.is_empty() {
+            let rest = self.batch.split_off(start);
+            full = self.flush();
+            self.batch = rest;
+        }
+
+        self.packets.push(packet);
+        let deadline = *self
+            .deadline
+            .get_or_insert_with(|| Instant::now() + self.window);
+
+        // Send the batch off once it's full, or once the oldest packet in it has waited long enough
+        if self.batch.len() >= self.max_len || Instant::now() >= deadline {
+            return Coalesced(full, self.flush());
+        }
+
+        Coalesced(full, None)
+    }
+
+    /// When the packets held back have to go out, if there are any
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Takes everything held back, as one `Batch` if there's more than one packet
+    pub fn flush(&mut self) -> Option<ProxyPacket> {
+        self.deadline = None;
+
+        let batch = self.batch.split().freeze();
+        match self.packets.len() {
+            0 => None,
+            // A batch of one is just the same packet with a longer header
+            1 => self.packets.pop(),
+            _ => {
+                self.packets.clear();
+                Some(ProxyPacket::Batch(batch))
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+}
+
+/// Packets that came out of a [`Coalescer`] and have to be written now, in order
+#[derive(Debug)]
+pub struct Coalesced(Option<ProxyPacket>, Option<ProxyPacket>);
+
+impl From<ProxyPacket> for Coalesced {
+    fn from(packet: ProxyPacket) -> Self {
+        Coalesced(Some(packet), None)
+    }
+}
+
+impl Iterator for Coalesced {
+    type Item = ProxyPacket;
+
+    fn next(&mut self) -> Option<ProxyPacket> {
+        self.0.take().or_else(|| self.1.take())
+    }
+}
+
+/// Returns true for packets that can be held back to go out in a `Batch`
+///
+/// `Hello`s have to make sense to the other end whatever it supports, `Fragment`s (sequenced or not)
+/// are only small when they're the end of something big that has been waiting long enough already,
+/// and time sync packets would take the wait for the link itself
+fn can_coalesce(packet: &ProxyPacket) -> bool {
+    match packet {
+        ProxyPacket::Sequenced(_, packet) => can_coalesce(packet),
+        ProxyPacket::Hello(_)
+        | ProxyPacket::HelloAck(_)
+        | ProxyPacket::Fragment(_)
+        | ProxyPacket::TimeRequest(_)
+        | ProxyPacket::TimeReply(..)
+        | ProxyPacket::Batch(_) => false,
+        _ => true,
+    }
+}
+
+/// Splits the contents of a `Batch` back up into the packets inside
+pub(crate) fn split_batch(mut batch: Bytes) -> Result<Vec<ProxyPacket>> {
+    let mut packets = Vec::new();
+
+    while !batch.is_empty() {
+        if batch.len() < BATCH_HEADER_LEN {
+            return Err(ProtoError::TruncatedPacket(BATCH_PACKET_ID));
+        }
+
+        let len = batch.get_u16_le() as usize;
+        if batch.len() < len {
+            return Err(ProtoError::TruncatedPacket(BATCH_PACKET_ID));
+        }
+
+        match ProxyPacket::decode(batch.split_to(len))? {
+            // A `Coalescer` never puts batches inside one another, but there's no harm in it
+            ProxyPacket::Batch(inner) => packets.extend(split_batch(inner)?),
+            packet => packets.push(packet),
+        }
+    }
+
+    Ok(packets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binary(len: usize) -> ProxyPacket {
+        ProxyPacket::Binary(Bytes::from(vec![7; len]))
+    }
+
+    fn debug(packets: impl IntoIterator<Item = ProxyPacket>) -> Vec<String> {
+        packets
+            .into_iter()
+            .map(|packet| format!("{packet:?}"))
+            .collect()
+    }
+
+    #[test]
+    fn batches_round_trip() {
+        let packets = vec![
+            ProxyPacket::Text("a".into()),
+            ProxyPacket::Ack(3),
+            binary(2).on_channel(2),
+        ];
+
+        let mut coalescer = Coalescer::new(Duration::from_secs(1), MAX_BATCH_LEN);
+        for packet in packets.clone() {
+            assert_eq!(coalescer.push(packet).count(), 0);
+        }
+        assert!(coalescer.deadline().is_some());
+
+        let Some(ProxyPacket::Batch(batch)) = coalescer.flush() else {
+            panic!("no batch");
+        };
+        assert!(coalescer.is_empty() && coalescer.deadline().is_none());
+        assert_eq!(debug(split_batch(batch).unwrap()), debug(packets));
+    }
+
+    #[test]
+    fn order_is_kept() {
+        let mut coalescer = Coalescer::new(Duration::from_secs(1), 100);
+        coalescer.push(binary(10));
+
+        // Something too big to hold back pushes out what was waiting in front of it
+        let out: Vec<_> = coalescer.push(binary(1000)).collect();
+        assert_eq!(debug(out), debug([binary(10), binary(1000)]));
+
+        // As does something that mustn't be batched
+        coalescer.push(binary(10));
+        coalescer.push(binary(20));
+        let out: Vec<_> = coalescer.push(ProxyPacket::TimeRequest(1)).collect();
+        assert!(matches!(
+            out[..],
+            [ProxyPacket::Batch(_), ProxyPacket::TimeRequest(1)]
+        ));
+
+        // A full batch goes out on its own, and the packet that didn't fit starts the next
+        let mut sent = 0;
+        for _ in 0..10 {
+            sent += coalescer.push(binary(30)).count();
+        }
+        assert!(sent >= 2);
+        assert!(!coalescer.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn packets_wait_at_most_the_window() {
+        let mut coalescer = Coalescer::new(Duration::from_millis(1), MAX_BATCH_LEN);
+        assert_eq!(coalescer.push(binary(3)).count(), 0);
+
+        tokio::time::advance(Duration::from_millis(2)).await;
+        let out: Vec<_> = coalescer.push(binary(4)).collect();
+        assert!(matches!(out[..], [ProxyPacket::Batch(_)]));
+        assert!(coalescer.is_empty());
+    }
+
+    #[test]
+    fn truncated_batches_are_refused() {
+        let batch = Bytes::from_static(&[5, 0, 0, b'a']);
+        assert!(matches!(
+            split_batch(batch),
+            Err(ProtoError::TruncatedPacket(BATCH_PACKET_ID))
+        ));
+    }
+}
@@ -0,0 +1,406 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::auth::FrameAuth;
+use crate::clock::monotonic_us;
+use crate::coalesce::split_batch;
+use crate::compression::{compress, decompress, is_compressible, COMPRESSED_PACKET_ID};
+use crate::fragment::Reassembler;
+use crate::framing::{check_frame_size, finish_frame, start_frame};
+use crate::{FrameDecoder, Framing, LinkStats, ProtoError, ProxyPacket, Result};
+
+/// Frames `ProxyPacket`s on async streams, e.g. `Framed<SerialStream, ProxyCodec>`
+///
+/// Frames that arrive corrupted or can't be decoded are dropped, so the only errors that come out of
+/// the stream are the ones that mean the link is gone. `Framed` can't carry on after an error anyway.
+///
+/// `Fragment`s are put back together and `Batch`es split up here too, so only whole packets come out
+/// of the stream.
+#[derive(Debug, Clone)]
+pub struct ProxyCodec {
+    frames: FrameDecoder,
+    /// Smallest `Text` or `Binary` payload to compress, once both ends have agreed on compression
+    compression_threshold: Option<usize>,
+    fragments: Reassembler,
+    /// Packets from the last `Batch` that haven't come out of the stream yet
+    batched: VecDeque<ProxyPacket>,
+    /// Whether to stamp frames with the time they're sent, once both ends have agreed on timestamps
+    timestamps: bool,
+    /// Key every frame is signed with and has to be signed with, if the link is authenticated
+    auth: Option<FrameAuth>,
+    stats: Arc<LinkStats>,
+}
+
+impl ProxyCodec {
+    pub fn new(framing: Framing, max_frame_size: u32) -> Self {
+        ProxyCodec {
+            frames: FrameDecoder::new(framing, max_frame_size),
+            compression_threshold: None,
+            fragments: Reassembler::default(),
+            batched: VecDeque::new(),
+            timestamps: false,
+            auth: None,
+            stats: Arc::new(LinkStats::default()),
+        }
+    }
+
+    /// Changes the largest frame payload sent or accepted, e.g. to what the handshake settled on
+    pub fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.frames.set_max_frame_size(max_frame_size);
+    }
+
+    /// Starts compressing `Text` and `Binary` packets of at least `threshold` bytes
+    ///
+    /// Only turn this on once the other end has advertised compression, compressed packets are always
+    /// accepted though
+    pub fn enable_compression(&mut self, threshold: usize) {
+        self.compression_threshold = Some(threshold);
+    }
+
+    /// Starts wrapping every packet but `Hello`s in a `Timestamped` packet as it's encoded
+    ///
+    /// Only turn this on once the other end has advertised timestamps, timestamped packets are always
+    /// accepted though, and measured against the clock estimate in the [`LinkStats`] if there is one
+    pub fn enable_timestamps(&mut self) {
+        self.timestamps = true;
+    }
+
+    /// Updates `stats` instead of counters of its own, so they can outlive this codec's connection
+    pub fn with_stats(mut self, stats: Arc<LinkStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Signs every frame sent with `auth`, and drops every frame received that isn't signed with it
+    ///
+    /// Set this up before the handshake, since the `Hello` has to be signed too
+    pub fn with_auth(mut self, auth: FrameAuth) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Ties frames to the sessions both ends picked in the handshake, if the link is authenticated
+    ///
+    /// Call this as soon as the handshake is over, see [`FrameAuth::bind`]
+    pub fn bind_auth(&mut self, local_session: u32, peer_session: u32) {
+        if let Some(auth) = &mut self.auth {
+            auth.bind(local_session, peer_session);
+        }
+    }
+
+    /// Counters for the link this codec is framing
+    pub fn stats(&self) -> Arc<LinkStats> {
+        self.stats.clone()
+    }
+
+    fn decode_packet(&mut self, src: &mut BytesMut) -> Result<Option<ProxyPacket>> {
+        loop {
+            if let Some(packet) = self.batched.pop_front() {
+                return Ok(Some(packet));
+            }
+
+            // Wait for a whole frame to arrive, skipping over anything between frames
+            let data = match self.frames.decode(src) {
+                Ok(Some(data)) => data,
+                Ok(None) => return Ok(None),
+                Err(e) if e.is_recoverable() => {
+                    self.stats.record_error(&e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            // Drop anything that didn't come from the other end, before spending any effort on it
+            let data = match &mut self.auth {
+                Some(auth) => match auth.verify(data) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        self.stats.record_error(&e);
+                        continue;
+                    }
+                },
+                None => data,
+            };
+
+            // Inflate compressed packets back into the packet inside
+            let data = match data.split_first() {
+                Some((&COMPRESSED_PACKET_ID, compressed)) => {
+                    match decompress(compressed, self.frames.max_frame_size()) {
+                        Ok(data) => Bytes::from(data),
+                        Err(e) => {
+                            self.stats.record_error(&e);
+                            continue;
+                        }
+                    }
+                }
+                _ => data,
+            };
+
+            // Decode the packet buffer
+            let packet = match ProxyPacket::decode(data) {
+                Ok(packet) => packet,
+                Err(e) if e.is_recoverable() => {
+                    self.stats.record_error(&e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            self.stats.record_received();
+
+            // Measure how long stamped packets took to get here, now that nothing else is in the way
+            let packet = match packet {
+                ProxyPacket::Timestamped(timestamp, packet) => {
+                    if let Some(clock) = self.stats.clock_estimate() {
+                        self.stats.record_latency(clock.latency_us(timestamp));
+                    }
+                    *packet
+                }
+                packet => packet,
+            };
+
+            // Hand out the packets in a batch one at a time
+            if let ProxyPacket::Batch(batch) = packet {
+                match split_batch(batch) {
+                    Ok(packets) => self.batched.extend(packets),
+                    Err(e) => self.stats.record_error(&e),
+                }
+                continue;
+            }
+
+            // Hold on to fragments until the packet they're part of is whole
+            let ProxyPacket::Fragment(fragment) = packet else {
+                return Ok(Some(packet));
+            };
+
+            let data = match self.fragments.push(fragment, self.frames.max_frame_size()) {
+                Ok(Some(data)) => data,
+                Ok(None) => continue,
+                Err(e) => {
+                    self.stats.record_error(&e);
+                    continue;
+                }
+            };
+
+            match ProxyPacket::decode(data) {
+                Ok(packet) => return Ok(Some(packet)),
+                Err(e) => self.stats.record_error(&e),
+            }
+        }
+    }
+}
+
+impl Decoder for ProxyCodec {
+    type Item = ProxyPacket;
+    type Error = ProtoError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ProxyPacket>> {
+        let len = src.len();
+        let packet = self.decode_packet(src);
+        self.stats.record_bytes_received(len - src.len());
+        packet
+    }
+}
+
+impl Encoder<ProxyPacket> for ProxyCodec {
+    type Error = ProtoError;
+
+    fn encode(&mut self, packet: ProxyPacket, dst: &mut BytesMut) -> Result<()> {
+        let framing = self.frames.framing();
+        let max_frame_size = self.frames.max_frame_size();
+
+        // Encode the payload straight into the write buffer, behind room for the frame header
+        let start = start_frame(dst, framing);
+        let payload_start = dst.len();
+        let encoded = if self.timestamps
+            && !matches!(packet, ProxyPacket::Hello(_) | ProxyPacket::HelloAck(_))
+        {
+            packet.encode_timestamped(monotonic_us(), dst)
+        } else {
+            packet.encode(dst)
+        };
+        if let Err(e) = encoded {
+            dst.truncate(start);
+            self.stats.record_error(&e);
+            return Err(e);
+        }
+
+        // Compress the payload if it's big enough to be worth it
+        let len = dst.len() - payload_start;
+        if let Some(threshold) = self.compression_threshold {
+            if len >= threshold && is_compressible(&packet) {
+                // The other end has to be able to hold the whole thing once it's inflated again
+                if let Err(e) = check_frame_size(len, max_frame_size) {
+                    dst.truncate(start);
+                    self.stats.record_error(&e);
+                    return Err(e);
+                }
+
+                match compress(&dst[payload_start..]) {
+                    Ok(Some(compressed)) => {
+                        dst.truncate(payload_start);
+                        dst.extend_from_slice(&compressed);
+                    }
+                    Ok(None) => {}
+                    Err(e) => {
+                        dst.truncate(start);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        // Sign exactly what goes on the wire, so the other end can check it before inflating anything
+        if let Some(auth) = &mut self.auth {
+            auth.sign_from(dst, payload_start);
+        }
+
+        // Wrap the payload in a frame, ready to be written
+        match finish_frame(dst, start, framing, max_frame_size) {
+            Ok(()) => {
+                self.stats.record_sent(dst.len() - start);
+                Ok(())
+            }
+            Err(e) => {
+                self.stats.record_error(&e);
+                Err(e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{encode_frame, Fragmenter, OpenRequest, FRAME_HEADER_LEN};
+
+    const FRAMINGS: [Framing; 2] = [Framing::LengthPrefixed, Framing::Cobs];
+
+    fn packets() -> Vec<ProxyPacket> {
+        vec![
+            ProxyPacket::Text("hello".into()),
+            ProxyPacket::Binary(Bytes::from(vec![0; 1000])),
+            ProxyPacket::Open(OpenRequest::new("pi")).on_channel(2),
+            ProxyPacket::Sequenced(3, Box::new(ProxyPacket::Text("x".repeat(300)))),
+            ProxyPacket::Ping(4),
+        ]
+    }
+
+    /// Decodes everything in `buf`, a few bytes at a time
+    fn decode_all(codec: &mut ProxyCodec, buf: &[u8]) -> Vec<String> {
+        let mut src = BytesMut::new();
+        let mut packets = Vec::new();
+
+        for chunk in buf.chunks(3) {
+            src.extend_from_slice(chunk);
+            while let Some(packet) = codec.decode(&mut src).unwrap() {
+                packets.push(format!("{packet:?}"));
+            }
+        }
+
+        packets
+    }
+
+    fn debug(packets: &[ProxyPacket]) -> Vec<String> {
+        packets.iter().map(|packet| format!("{packet:?}")).collect()
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        for framing in FRAMINGS {
+            let codecs = || {
+                let mut compressed = ProxyCodec::new(framing, 4096);
+                compressed.enable_compression(64);
+                let mut timestamped = ProxyCodec::new(framing, 4096);
+                timestamped.enable_timestamps();
+                let signed = ProxyCodec::new(framing, 4096).with_auth(FrameAuth::new(b"key"));
+
+                [ProxyCodec::new(framing, 4096), compressed, timestamped, signed]
+            };
+
+            for (mut tx, mut rx) in codecs().into_iter().zip(codecs()) {
+                let mut buf = BytesMut::new();
+                for packet in packets() {
+                    tx.encode(packet, &mut buf).unwrap();
+                }
+
+                assert_eq!(decode_all(&mut rx, &buf), debug(&packets()), "{tx:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn decoder_resyncs_after_noise() {
+        for framing in FRAMINGS {
+            let stats = Arc::new(LinkStats::default());
+            let mut codec = ProxyCodec::new(framing, 4096).with_stats(stats.clone());
+
+            let mut buf = BytesMut::from(&[0x30, 0x03, 0x00, 0x42][..]);
+            codec.encode(ProxyPacket::Text("first".into()), &mut buf).unwrap();
+
+            let start = buf.len();
+            codec.encode(ProxyPacket::Text("corrupted".into()), &mut buf).unwrap();
+            buf[start + FRAME_HEADER_LEN + 2] ^= 0x01;
+
+            // Makes it across intact, but isn't a packet
+            encode_frame(&[200], framing, 4096, &mut buf).unwrap();
+
+            codec.encode(ProxyPacket::Text("second".into()), &mut buf).unwrap();
+
+            assert_eq!(
+                decode_all(&mut codec, &buf),
+                ["Text(\"first\")", "Text(\"second\")"],
+                "{framing:?}"
+            );
+
+            // The noise up front can look like a broken frame or two as well
+            let snapshot = stats.snapshot();
+            assert!(snapshot.checksum_failures >= 1);
+            assert_eq!(snapshot.decode_errors, 1);
+        }
+    }
+
+    #[test]
+    fn oversized_packets_are_dropped() {
+        for framing in FRAMINGS {
+            let mut big = ProxyCodec::new(framing, 4096);
+            let mut small = ProxyCodec::new(framing, 64);
+
+            let mut buf = BytesMut::new();
+            assert!(matches!(
+                small.encode(ProxyPacket::Binary(Bytes::from(vec![1; 100])), &mut buf),
+                Err(ProtoError::FrameTooLarge { .. })
+            ));
+            assert!(buf.is_empty());
+
+            big.encode(ProxyPacket::Binary(Bytes::from(vec![1; 1000])), &mut buf).unwrap();
+            big.encode(ProxyPacket::Text("after".into()), &mut buf).unwrap();
+
+            assert_eq!(decode_all(&mut small, &buf), ["Text(\"after\")"], "{framing:?}");
+            assert_eq!(small.stats().snapshot().frames_too_large, 2);
+        }
+    }
+
+    #[test]
+    fn fragments_are_put_back_together() {
+        let mut codec = ProxyCodec::new(Framing::default(), 4096);
+        let mut fragmenter = Fragmenter::new(100, 4096);
+        let big = ProxyPacket::Binary(Bytes::from((0..=255).cycle().take(1000).collect::<Vec<u8>>()));
+
+        assert!(fragmenter.push(big.clone()).unwrap().is_none());
+
+        let mut buf = BytesMut::new();
+        codec.encode(ProxyPacket::Text("before".into()), &mut buf).unwrap();
+        while let Some(fragment) = fragmenter.next_packet() {
+            codec.encode(fragment, &mut buf).unwrap();
+            codec.encode(ProxyPacket::Ping(1), &mut buf).unwrap();
+        }
+
+        let decoded = decode_all(&mut codec, &buf);
+        assert_eq!(decoded.len(), 13);
+        assert_eq!(decoded[0], "Text(\"before\")");
+        assert_eq!(decoded[11], format!("{big:?}"));
+    }
+}
@@ -0,0 +1,56 @@
+use std::io::{Read, Write};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+use crate::framing::check_frame_size;
+use crate::{ProtoError, ProxyPacket, Result};
+
+/// Packet ID marking a payload that holds another, deflated, packet
+///
+/// Never decoded into a `ProxyPacket` of its own, the codec unwraps it before decoding what's inside
+pub(crate) const COMPRESSED_PACKET_ID: u8 = 10;
+
+/// Smallest payload worth compressing unless configured otherwise, in bytes
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 256;
+
+/// Returns true for packets carrying WS text or binary data (or pieces of it), which are worth
+/// compressing when large
+pub(crate) fn is_compressible(packet: &ProxyPacket) -> bool {
+    match packet {
+        ProxyPacket::Text(_)
+        | ProxyPacket::Binary(_)
+        | ProxyPacket::Fragment(_)
+        | ProxyPacket::Batch(_) => true,
+        ProxyPacket::Sequenced(_, packet)
+        | ProxyPacket::Channel(_, packet)
+        | ProxyPacket::Timestamped(_, packet) => is_compressible(packet),
+        _ => false,
+    }
+}
+
+/// Deflates an encoded packet, returning `None` if compressing it didn't make it any smaller
+pub(crate) fn compress(payload: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut encoder = DeflateEncoder::new(vec![COMPRESSED_PACKET_ID], Compression::fast());
+    encoder.write_all(payload)?;
+    let compressed = encoder.finish()?;
+
+    Ok((compressed.len() < payload.len()).then_some(compressed))
+}
+
+/// Inflates the body of a compressed packet back into the encoded packet inside it
+///
+/// Stops as soon as the packet would be bigger than `max_frame_size`, so a small frame can't inflate
+/// into gigabytes
+pub(crate) fn decompress(bytes: &[u8], max_frame_size: u32) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    DeflateDecoder::new(bytes)
+        .take(max_frame_size as u64 + 1)
+        .read_to_end(&mut payload)
+        .map_err(|_| ProtoError::CorruptCompressedPacket)?;
+
+    check_frame_size(payload.len(), max_frame_size)?;
+
+    Ok(payload)
+}
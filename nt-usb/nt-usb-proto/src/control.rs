@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+
+use crate::{LinkStatsSnapshot, ProxyPacket, Result};
+
+/// How much an end of the link prints, from only the errors up to everything
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!(
+                "Unknown log level `{}`, expected one of error, warn, info or debug",
+                s
+            )),
+        }
+    }
+}
+
+/// Something for the other end of the link to do, sent in a `ControlRequest` and answered with a
+/// [`ControlResponse`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// Read the config file again, taking effect the next time the link comes up
+    ReloadConfig,
+    /// Drop the NT session and start a fresh one
+    RestartNtSession,
+    /// Send back the link stats
+    ReportStats,
+    /// Print more or less from now on
+    SetLogLevel { level: LogLevel },
+}
+
+impl ControlCommand {
+    /// Wraps the command up to send, under an ID of the sender's choosing that the response comes back with
+    pub fn to_packet(&self, id: u32) -> ProxyPacket {
+        ProxyPacket::ControlRequest(id, to_body(self))
+    }
+
+    /// Decodes the body of a `ControlRequest`
+    ///
+    /// Fails for commands this build doesn't know about, which should still be answered so the sender
+    /// isn't left waiting
+    pub fn decode(body: &[u8]) -> Result<ControlCommand> {
+        Ok(serde_json::from_slice(body)?)
+    }
+}
+
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlCommand::ReloadConfig => f.write_str("reload_config"),
+            ControlCommand::RestartNtSession => f.write_str("restart_nt_session"),
+            ControlCommand::ReportStats => f.write_str("report_stats"),
+            ControlCommand::SetLogLevel { level } => write!(f, "set_log_level {}", level),
+        }
+    }
+}
+
+/// Parses a command the way it's displayed, e.g. `report_stats` or `set_log_level debug`
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, String> {
+        let mut words = s.split_whitespace();
+        let command = match (words.next(), words.next()) {
+            (Some("reload_config"), None) => ControlCommand::ReloadConfig,
+            (Some("restart_nt_session"), None) => ControlCommand::RestartNtSession,
+            (Some("report_stats"), None) => ControlCommand::ReportStats,
+            (Some("set_log_level"), Some(level)) => ControlCommand::SetLogLevel {
+                level: level.parse()?,
+            },
+            _ => return Err(format!(
+                "Unknown command `{}`, expected one of reload_config, restart_nt_session, report_stats or set_log_level <level>",
+                s.trim()
+            )),
+        };
+
+        match words.next() {
+            Some(_) => Err(format!("Too many arguments to `{}`", command)),
+            None => Ok(command),
+        }
+    }
+}
+
+/// How a [`ControlCommand`] went, sent back in a `ControlResponse`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ControlResponse {
+    /// The command was carried out
+    Done { message: String },
+    /// The link stats, answering `ReportStats`
+    Stats { stats: LinkStatsSnapshot },
+    /// The command couldn't be carried out, or wasn't understood
+    Failed { message: String },
+}
+
+impl ControlResponse {
+    /// Wraps the response up to send, under the ID of the request it answers
+    pub fn to_packet(&self, id: u32) -> ProxyPacket {
+        ProxyPacket::ControlResponse(id, to_body(self))
+    }
+
+    /// Decodes the body of a `ControlResponse`
+    pub fn decode(body: &[u8]) -> Result<ControlResponse> {
+        Ok(serde_json::from_slice(body)?)
+    }
+}
+
+impl fmt::Display for ControlResponse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlResponse::Done { message } => f.write_str(message),
+            ControlResponse::Stats { stats } => write!(f, "USB link stats: {}", stats),
+            ControlResponse::Failed { message } => write!(f, "Failed: {}", message),
+        }
+    }
+}
+
+/// How long the other end has to answer a command before it's given up on
+pub const CONTROL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Commands sent to the other end that haven't been answered yet
+///
+/// IDs carry on from one connection to the next, so a late answer from an old connection can't be
+/// mistaken for one to a newer command.
+#[derive(Debug, Default)]
+pub struct PendingCommands {
+    next_id: u32,
+    sent: HashMap<u32, (ControlCommand, Instant)>,
+}
+
+impl PendingCommands {
+    /// Remembers `command` as sent at `now`, returning the packet to send it in
+    pub fn send(&mut self, command: ControlCommand, now: Instant) -> ProxyPacket {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let packet = command.to_packet(id);
+        self.sent.insert(id, (command, now));
+        packet
+    }
+
+    /// Takes the command a `ControlResponse` with `id` answers, if it's still waiting for one
+    pub fn answer(&mut self, id: u32) -> Option<ControlCommand> {
+        self.sent.remove(&id).map(|(command, _)| command)
+    }
+
+    /// Gives up on every command sent more than `timeout` before `now`, returning them
+    pub fn expire(&mut self, now: Instant, timeout: Duration) -> Vec<ControlCommand> {
+        let expired: Vec<u32> = self
+            .sent
+            .iter()
+            .filter(|(_, (_, sent))| now.saturating_duration_since(*sent) >= timeout)
+            .map(|(id, _)| *id)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|id| self.answer(id))
+            .collect()
+    }
+
+    /// Gives up on every command, for when the link went down before they were answered
+    pub fn clear(&mut self) -> Vec<ControlCommand> {
+        self.sent.drain().map(|(_, (command, _))| command).collect()
+    }
+}
+
+fn to_body<T: Serialize>(value: &T) -> Bytes {
+    // Nothing in a command or response has a map key that isn't a string, so this can't fail
+    Bytes::from(serde_json::to_vec(value).expect("control messages always serialize"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answered_commands_are_forgotten() {
+        let mut pending = PendingCommands::default();
+        let now = Instant::now();
+
+        let ProxyPacket::ControlRequest(id, _) = pending.send(ControlCommand::ReportStats, now)
+        else {
+            panic!("commands are sent as requests");
+        };
+        assert_eq!(pending.answer(id), Some(ControlCommand::ReportStats));
+        assert_eq!(pending.answer(id), None);
+    }
+
+    #[test]
+    fn unanswered_commands_expire() {
+        let mut pending = PendingCommands::default();
+        let now = Instant::now();
+        pending.send(ControlCommand::ReportStats, now);
+        pending.send(ControlCommand::ReloadConfig, now + CONTROL_TIMEOUT);
+
+        let expired = pending.expire(now + CONTROL_TIMEOUT, CONTROL_TIMEOUT);
+        assert_eq!(expired, vec![ControlCommand::ReportStats]);
+
+        // Whatever's left goes when the link does
+        assert_eq!(pending.clear(), vec![ControlCommand::ReloadConfig]);
+        assert!(pending.clear().is_empty());
+    }
+}
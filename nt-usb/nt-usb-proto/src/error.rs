@@ -0,0 +1,141 @@
+use std::fmt;
+use std::io;
+use std::string::FromUtf8Error;
+
+use crate::LinkInfo;
+
+/// Shorthand for results whose error is a [`ProtoError`]
+pub type Result<T> = std::result::Result<T, ProtoError>;
+
+/// Everything that can go wrong reading, writing or negotiating the USB link
+#[derive(Debug)]
+pub enum ProtoError {
+    /// Reading from or writing to the link failed, e.g. because the cable was pulled
+    Io(io::Error),
+    /// A frame with no packet in it at all
+    EmptyFrame,
+    /// A packet with an ID this build doesn't know about
+    UnknownPacketId(u8),
+    /// A packet (identified by its ID) that ended before all of its fields were read
+    TruncatedPacket(u8),
+    /// A `Text` packet that isn't valid UTF-8
+    InvalidUtf8(FromUtf8Error),
+    /// A frame with a bigger payload than is allowed on the link
+    FrameTooLarge { len: usize, max: usize },
+    /// A frame that didn't make it across intact
+    ChecksumMismatch,
+    /// A compressed packet that couldn't be inflated
+    CorruptCompressedPacket,
+    /// A fragmented message (identified by its message number) that lost one of its fragments
+    IncompleteMessage(u16),
+    /// An `Open` request with more subprotocols, or a longer string, than its packet can carry
+    OpenRequestTooLarge {
+        field: &'static str,
+        len: usize,
+        max: usize,
+    },
+    /// A control command or response body that couldn't be decoded, e.g. from a newer build
+    InvalidControlMessage(serde_json::Error),
+    /// A frame without a valid authentication tag, e.g. from something that isn't the other end of the link
+    AuthenticationFailed,
+    /// A frame with a valid authentication tag that was already accepted, or is older than one that was,
+    /// e.g. recorded off the link and played back into it
+    ReplayedFrame,
+    /// Nothing but frames failing authentication came from the other end during the handshake, e.g.
+    /// because it has a different key
+    KeyMismatch,
+    /// The two ends of the link have no protocol version in common
+    VersionMismatch {
+        local: Box<LinkInfo>,
+        peer: Box<LinkInfo>,
+    },
+}
+
+impl ProtoError {
+    /// Returns true if the link is still usable after this error, and only the packet it came from was lost
+    ///
+    /// Anything else means the link has to be torn down and opened again
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(
+            self,
+            ProtoError::Io(_) | ProtoError::KeyMismatch | ProtoError::VersionMismatch { .. }
+        )
+    }
+}
+
+impl fmt::Display for ProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtoError::Io(e) => write!(f, "USB link I/O error: {}", e),
+            ProtoError::EmptyFrame => write!(f, "Frame has no packet in it"),
+            ProtoError::UnknownPacketId(id) => write!(f, "Unknown packet ID {}", id),
+            ProtoError::TruncatedPacket(id) => write!(f, "Packet with ID {} ended early", id),
+            ProtoError::InvalidUtf8(e) => write!(f, "Text packet is not valid UTF-8: {}", e),
+            ProtoError::FrameTooLarge { len, max } => write!(
+                f,
+                "Frame of {} bytes is larger than the {} bytes allowed",
+                len, max
+            ),
+            ProtoError::ChecksumMismatch => write!(f, "Frame checksum mismatch"),
+            ProtoError::CorruptCompressedPacket => {
+                write!(f, "Compressed packet could not be inflated")
+            }
+            ProtoError::IncompleteMessage(message) => {
+                write!(f, "Fragmented message {} is missing a fragment", message)
+            }
+            ProtoError::OpenRequestTooLarge { field, len, max } => write!(
+                f,
+                "Open request {} of {} is over the {} its packet can carry",
+                field, len, max
+            ),
+            ProtoError::InvalidControlMessage(e) => {
+                write!(f, "Control message could not be decoded: {}", e)
+            }
+            ProtoError::AuthenticationFailed => write!(f, "Frame failed authentication"),
+            ProtoError::ReplayedFrame => write!(f, "Frame was already received once"),
+            ProtoError::KeyMismatch => write!(
+                f,
+                "Every frame from the other end failed authentication. Check both ends have the same auth_key."
+            ),
+            ProtoError::VersionMismatch { local, peer } => write!(
+                f,
+                "Refusing USB link: `{}` speaks protocol v{}-v{}, but `{}` speaks v{}-v{}. Update both ends to the same build.",
+                local.build_version,
+                local.min_protocol_version,
+                local.protocol_version,
+                peer.build_version,
+                peer.min_protocol_version,
+                peer.protocol_version,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ProtoError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtoError::Io(e) => Some(e),
+            ProtoError::InvalidUtf8(e) => Some(e),
+            ProtoError::InvalidControlMessage(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProtoError {
+    fn from(e: io::Error) -> Self {
+        ProtoError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for ProtoError {
+    fn from(e: serde_json::Error) -> Self {
+        ProtoError::InvalidControlMessage(e)
+    }
+}
+
+impl From<FromUtf8Error> for ProtoError {
+    fn from(e: FromUtf8Error) -> Self {
+        ProtoError::InvalidUtf8(e)
+    }
+}
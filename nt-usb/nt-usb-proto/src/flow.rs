@@ -0,0 +1,253 @@
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::channel::mpsc::UnboundedSender;
+use futures::task::AtomicWaker;
+use futures::Stream;
+
+use crate::ProxyPacket;
+
+/// Data packets the other end may send ahead of what's been passed on, unless configured otherwise
+pub const DEFAULT_CREDIT_WINDOW: u32 = 64;
+
+/// How long to go without credit before asking the other end for it again, in case a `Credit` got lost
+pub const CREDIT_PROBE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Sending half of credit based flow control, counting data packets against what the other end allows
+///
+/// Credits are absolute, so a `Credit` carries the total number of data packets the other end will
+/// ever have room for. Losing one only holds things up until the next one, or until a `CreditProbe`
+/// asks for it again.
+///
+/// Shared behind an `Arc` between the task reading the link, which hands over `Credit`s, and the
+/// task writing to it, which pulls data packets through a [`CreditGate`].
+#[derive(Debug, Default)]
+pub struct SendCredits {
+    state: Mutex<SendState>,
+    /// Wakes the gate waiting for credit
+    waker: AtomicWaker,
+}
+
+#[derive(Debug, Default)]
+struct SendState {
+    /// Data packets the other end has room for, counted since the link came up
+    limit: u32,
+    /// Data packets taken through the gate, counted since the link came up
+    taken: u32,
+}
+
+impl SendState {
+    fn is_exhausted(&self) -> bool {
+        (self.limit.wrapping_sub(self.taken) as i32) <= 0
+    }
+}
+
+impl SendCredits {
+    /// Starts out with no credit at all, until the other end sends its first `Credit`
+    pub fn new() -> Self {
+        SendCredits::default()
+    }
+
+    /// Raises the limit to what the other end sent in a `Credit`, ignoring any that arrive out of date
+    pub fn on_credit(&self, limit: u32) {
+        let mut state = self.state.lock().unwrap();
+        if (limit.wrapping_sub(state.limit) as i32) > 0 {
+            state.limit = limit;
+        }
+        drop(state);
+
+        self.waker.wake();
+    }
+
+    /// Gives back the credit for a data packet that was taken but never sent, e.g. because it was filtered out
+    pub fn refund(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.taken = state.taken.wrapping_sub(1);
+        drop(state);
+
+        self.waker.wake();
+    }
+
+    /// Returns true if no more data packets can be sent until the other end makes room
+    pub fn is_exhausted(&self) -> bool {
+        self.state.lock().unwrap().is_exhausted()
+    }
+
+    /// Only lets packets out of `stream` while there's credit for them, taking one for each
+    pub fn gate<S>(self: &Arc<Self>, stream: S) -> CreditGate<S> {
+        CreditGate {
+            stream,
+            credits: self.clone(),
+        }
+    }
+
+    fn try_take(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.is_exhausted() {
+            return false;
+        }
+
+        state.taken = state.taken.wrapping_add(1);
+        true
+    }
+}
+
+/// Data packets waiting on credit from the other end, see [`SendCredits::gate`]
+///
+/// Nothing is pulled out of the stream underneath while there's no credit, so whatever is feeding it
+/// gets held up instead, as far back as the bounded channels go
+#[derive(Debug)]
+pub struct CreditGate<S> {
+    stream: S,
+    credits: Arc<SendCredits>,
+}
+
+impl<S: Stream + Unpin> Stream for CreditGate<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        // Check again after registering, in case credit turned up in between
+        if self.credits.is_exhausted() {
+            self.credits.waker.register(cx.waker());
+            if self.credits.is_exhausted() {
+                return Poll::Pending;
+            }
+        }
+
+        let item = Pin::new(&mut self.stream).poll_next(cx);
+        if let Poll::Ready(Some(_)) = item {
+            // Only this task ever takes credit, so it can't have run out since the check above
+            self.credits.try_take();
+        }
+
+        item
+    }
+}
+
+/// Receiving half of credit based flow control, handing out credit as data packets are passed on
+#[derive(Debug, Clone)]
+pub struct ReceiveCredits {
+    /// Data packets the other end may send ahead of what's been passed on
+    window: u32,
+    /// Data packets passed on, counted since the link came up
+    delivered: u32,
+    /// Limit sent in the last `Credit`
+    granted: u32,
+}
+
+impl ReceiveCredits {
+    pub fn new(window: u32) -> Self {
+        ReceiveCredits {
+            window,
+            delivered: 0,
+            granted: 0,
+        }
+    }
+
+    /// Makes a `Credit` for everything there's room for right now
+    ///
+    /// Send one as soon as the link comes up, and in answer to every `CreditProbe`
+    pub fn grant(&mut self) -> ProxyPacket {
+        self.granted = self.delivered.wrapping_add(self.window);
+        ProxyPacket::Credit(self.granted)
+    }
+
+    /// Counts a data packet as passed on, returning a `Credit` to send once half the window is used up
+    ///
+    /// Topping up a half empty window rather than after every packet keeps `Credit`s off the link
+    /// most of the time, without the other end ever having to wait for one
+    pub fn on_delivered(&mut self) -> Option<ProxyPacket> {
+        self.delivered = self.delivered.wrapping_add(1);
+
+        let remaining = self.granted.wrapping_sub(self.delivered) as i32;
+        (remaining <= (self.window / 2) as i32).then(|| self.grant())
+    }
+}
+
+/// Holds on to the room a data packet from the other end takes up, until it's dropped
+///
+/// Goes wherever the packet does, so the other end only gets credit for it back once the packet has
+/// really been used up, e.g. written to its WS connection, rather than just queued up somewhere
+#[derive(Debug, Default)]
+pub struct DeliveryReceipt(Option<UnboundedSender<()>>);
+
+impl DeliveryReceipt {
+    pub(crate) fn new(delivered: Option<UnboundedSender<()>>) -> Self {
+        DeliveryReceipt(delivered)
+    }
+}
+
+impl Drop for DeliveryReceipt {
+    fn drop(&mut self) {
+        if let Some(delivered) = &self.0 {
+            // Fails once the connection it came in on has gone, and there's nobody left to tell
+            let _ = delivered.unbounded_send(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{FutureExt, StreamExt};
+
+    use super::*;
+
+    #[test]
+    fn gate_waits_for_credit() {
+        let credits = Arc::new(SendCredits::new());
+        let mut gate = credits.gate(futures::stream::iter(0..10));
+
+        assert!(gate.next().now_or_never().is_none());
+
+        credits.on_credit(2);
+        assert_eq!(gate.next().now_or_never(), Some(Some(0)));
+        assert_eq!(gate.next().now_or_never(), Some(Some(1)));
+        assert!(gate.next().now_or_never().is_none());
+        assert!(credits.is_exhausted());
+
+        // Something that never made it onto the link doesn't use up credit
+        credits.refund();
+        assert_eq!(gate.next().now_or_never(), Some(Some(2)));
+
+        // Credit that arrives late doesn't take any back
+        credits.on_credit(1);
+        assert!(credits.is_exhausted());
+    }
+
+    #[test]
+    fn credit_is_granted_ahead() {
+        let mut receive = ReceiveCredits::new(4);
+        assert!(matches!(receive.grant(), ProxyPacket::Credit(4)));
+
+        // More credit goes out once half the window has been passed on
+        assert!(receive.on_delivered().is_none());
+        assert!(matches!(
+            receive.on_delivered(),
+            Some(ProxyPacket::Credit(6))
+        ));
+    }
+
+    #[test]
+    fn credits_round_trip() {
+        let send = Arc::new(SendCredits::new());
+        let mut receive = ReceiveCredits::new(2);
+        let mut gate = send.gate(futures::stream::iter(0..4));
+
+        let ProxyPacket::Credit(limit) = receive.grant() else {
+            panic!("no credit");
+        };
+        send.on_credit(limit);
+
+        let mut passed_on = Vec::new();
+        while let Some(Some(n)) = gate.next().now_or_never() {
+            passed_on.push(n);
+            if let Some(ProxyPacket::Credit(limit)) = receive.on_delivered() {
+                send.on_credit(limit);
+            }
+        }
+
+        assert_eq!(passed_on, [0, 1, 2, 3]);
+    }
+}
@@ -0,0 +1,435 @@
+use std::collections::VecDeque;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::framing::check_frame_size;
+use crate::{ProtoError, ProxyPacket, Result, AUTH_COUNTER_LEN, AUTH_TAG_LEN};
+
+/// Packet ID of `Fragment` packets, for reporting truncated ones
+const FRAGMENT_PACKET_ID: u8 = 14;
+
+/// Bytes of a `Fragment` packet that aren't the piece of the message it carries
+pub const FRAGMENT_HEADER_LEN: usize = 6;
+
+/// Bytes a `Fragment` can grow by on its way into a frame, on top of the piece of the message it carries
+///
+/// Its own header, the `Sequenced` and `Timestamped` packets it can be wrapped in, and the frame
+/// counter and authentication tag on the end of the frame
+const FRAGMENT_OVERHEAD: usize = FRAGMENT_HEADER_LEN + 5 + 9 + AUTH_COUNTER_LEN + AUTH_TAG_LEN;
+
+/// Largest piece of a message sent in one `Fragment` unless configured otherwise, in bytes
+///
+/// About 45 ms on the wire at 115200 baud, which is as long as anything else has to wait behind it
+pub const DEFAULT_FRAGMENT_SIZE: usize = 512;
+
+/// Most messages either end can be in the middle of sending before the oldest is given up on
+const MAX_PARTIAL_MESSAGES: usize = 8;
+
+/// One piece of an encoded packet too big to send in one go
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fragment {
+    /// Which message this is a piece of, counting up from 0 on each connection
+    pub message: u16,
+    /// Where this piece goes in the message, counting up from 0
+    pub index: u16,
+    /// Whether this is the last piece of the message
+    pub last: bool,
+    pub data: Bytes,
+}
+
+impl Fragment {
+    pub(crate) fn encode(&self, dst: &mut BytesMut) {
+        dst.put_u16_le(self.message);
+        dst.put_u16_le(self.index);
+        dst.put_u8(self.last as u8);
+        dst.extend_from_slice(&self.data);
+    }
+
+    pub(crate) fn decode(mut bytes: Bytes) -> Result<Fragment> {
+        if bytes.len() < FRAGMENT_HEADER_LEN - 1 {
+            return Err(ProtoError::TruncatedPacket(FRAGMENT_PACKET_ID));
+        }
+
+        Ok(Fragment {
+            message: bytes.get_u16_le(),
+            index: bytes.get_u16_le(),
+            last: bytes.get_u8() & 1 != 0,
+            data: bytes,
+        })
+    }
+}
+
+/// Returns true for packets carrying WS binary data, which are split up when large
+fn is_fragmentable(packet: &ProxyPacket) -> bool {
+    match packet {
+        ProxyPacket::Binary(_) => true,
+        ProxyPacket::Channel(_, packet) => is_fragmentable(packet),
+        _ => false,
+    }
+}
+
+/// Something waiting its turn on a channel that's part way through sending a message in fragments
+#[derive(Debug)]
+enum Pending {
+    Fragments(VecDeque<Fragment>),
+    /// A packet sent after a message still going out in fragments, which has to arrive after it
+    Whole(ProxyPacket),
+}
+
+/// Splits large `Binary` packets into `Fragment`s, and hands them out a few at a time so everything
+/// else can be sent in between
+///
+/// Only messages on different channels take turns. Each channel sends its messages one after the
+/// other, and anything sent on it after a message that's still going out in fragments waits for it,
+/// so NT4 messages never arrive out of order.
+///
+/// Packets are split up before they're sequenced, so each fragment is acknowledged and resent on its
+/// own. Only use this once the other end has advertised `Capabilities::FRAGMENTATION`
+#[derive(Debug)]
+pub struct Fragmenter {
+    fragment_size: usize,
+    max_frame_size: u32,
+    next_message: u16,
+    /// Everything still to send, one queue per channel, with the channels being sent at the front
+    pending: VecDeque<(u8, VecDeque<Pending>)>,
+}
+
+impl Fragmenter {
+    /// Splits packets bigger than `fragment_size` bytes, which is shrunk to fit in `max_frame_size` if needed
+    pub fn new(fragment_size: usize, max_frame_size: u32) -> Self {
+        // Fragments are numbered with a u16, so there can't be too many of them either
+        let smallest = max_frame_size as usize / u16::MAX as usize + 1;
+        let largest = (max_frame_size as usize).saturating_sub(FRAGMENT_OVERHEAD);
+
+        Fragmenter {
+            fragment_size: fragment_size.clamp(smallest, largest.max(smallest)),
+            max_frame_size,
+            next_message: 0,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Queues up `packet` to be sent in fragments if it's big enough, or behind a message still being
+    /// sent on its channel, and hands it back otherwise
+    ///
+    /// Reassembled messages are held to the same maximum size as frames, so a packet too big to
+    /// send whole is still refused
+    pub fn push(&mut self, packet: ProxyPacket) -> Result<Option<ProxyPacket>> {
+        // The link's own packets don't belong to any channel
+        if packet.is_link_control() {
+            return Ok(Some(packet));
+        }
+
+        let channel = match &packet {
+            ProxyPacket::Channel(channel, _) => *channel,
+            _ => 0,
+        };
+        let busy = self.pending.iter().position(|(c, _)| *c == channel);
+
+        let pending = match self.split(&packet)? {
+            Some(fragments) => Pending::Fragments(fragments),
+            None if busy.is_some() => Pending::Whole(packet),
+            None => return Ok(Some(packet)),
+        };
+
+        match busy {
+            Some(i) => self.pending[i].1.push_back(pending),
+            None => self.pending.push_back((channel, VecDeque::from([pending]))),
+        }
+
+        Ok(None)
+    }
+
+    /// Splits `packet` up if it's big enough to need it
+    fn split(&mut self, packet: &ProxyPacket) -> Result<Option<VecDeque<Fragment>>> {
+        if !is_fragmentable(packet) {
+            return Ok(None);
+        }
+
+        let mut payload = BytesMut::new();
+        packet.encode(&mut payload)?;
+        check_frame_size(payload.len(), self.max_frame_size)?;
+
+        if payload.len() <= self.fragment_size {
+            return Ok(None);
+        }
+
+        // Split the encoded packet up, marking the last piece so the other end knows when it's whole
+        let message = self.next_message;
+        self.next_message = self.next_message.wrapping_add(1);
+
+        let payload = payload.freeze();
+        let count = payload.len().div_ceil(self.fragment_size);
+        let fragments = (0..count)
+            .map(|index| {
+                let start = index * self.fragment_size;
+                let end = payload.len().min(start + self.fragment_size);
+
+                Fragment {
+                    message,
+                    index: index as u16,
+                    last: index + 1 == count,
+                    data: payload.slice(start..end),
+                }
+            })
+            .collect();
+
+        Ok(Some(fragments))
+    }
+
+    /// Takes the next fragment to send, or a packet that was waiting on one, taking turns between channels
+    ///
+    /// Only as many channels as the other end can put messages back together for at once take turns,
+    /// the rest wait for one of them to finish
+    pub fn next_packet(&mut self) -> Option<ProxyPacket> {
+        let (channel, mut queue) = self.pending.pop_front()?;
+
+        let packet = match queue.pop_front()? {
+            Pending::Fragments(mut fragments) => {
+                let fragment = fragments.pop_front()?;
+                if !fragments.is_empty() {
+                    queue.push_front(Pending::Fragments(fragments));
+                }
+                ProxyPacket::Fragment(fragment)
+            }
+            Pending::Whole(packet) => packet,
+        };
+
+        if !queue.is_empty() {
+            let turn = self.pending.len().min(MAX_PARTIAL_MESSAGES - 1);
+            self.pending.insert(turn, (channel, queue));
+        }
+
+        Some(packet)
+    }
+
+    /// Number of messages and packets waiting on them still to send
+    pub fn len(&self) -> usize {
+        self.pending.iter().map(|(_, queue)| queue.len()).sum()
+    }
+
+    /// Returns true if there's nothing left to send
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+}
+
+/// A message the other end is part way through sending
+#[derive(Debug, Clone)]
+struct PartialMessage {
+    message: u16,
+    next_index: u16,
+    data: Vec<u8>,
+}
+
+/// Puts messages split up by a [`Fragmenter`] back together
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Reassembler {
+    partial: VecDeque<PartialMessage>,
+}
+
+impl Reassembler {
+    /// Adds `fragment` to its message, returning the encoded packet once the message is whole
+    ///
+    /// A message that's missing a fragment, or grows past `max_len`, is dropped along with the error
+    pub(crate) fn push(&mut self, fragment: Fragment, max_len: u32) -> Result<Option<Bytes>> {
+        let existing = self
+            .partial
+            .iter()
+            .position(|partial| partial.message == fragment.message)
+            .and_then(|i| self.partial.remove(i));
+
+        // The first fragment always starts a message over, even if the ID has come back around
+        let mut partial = match existing {
+            Some(partial) if fragment.index != 0 => partial,
+            _ => PartialMessage {
+                message: fragment.message,
+                next_index: 0,
+                data: Vec::new(),
+            },
+        };
+
+        if fragment.index != partial.next_index {
+            return Err(ProtoError::IncompleteMessage(fragment.message));
+        }
+
+        check_frame_size(partial.data.len() + fragment.data.len(), max_len)?;
+        partial.data.extend_from_slice(&fragment.data);
+        partial.next_index = partial.next_index.wrapping_add(1);
+
+        if fragment.last {
+            return Ok(Some(Bytes::from(partial.data)));
+        }
+
+        // The other end only interleaves a few messages at a time, so the oldest has been abandoned
+        if self.partial.len() == MAX_PARTIAL_MESSAGES {
+            self.partial.pop_front();
+        }
+        self.partial.push_back(partial);
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn binary(len: usize, byte: u8) -> ProxyPacket {
+        ProxyPacket::Binary(Bytes::from(vec![byte; len]))
+    }
+
+    /// Feeds every fragment `fragmenter` has into `reassembler`, returning the packets in the order they
+    /// came out whole
+    fn reassemble(fragmenter: &mut Fragmenter, reassembler: &mut Reassembler) -> Vec<String> {
+        let mut packets = Vec::new();
+
+        while let Some(packet) = fragmenter.next_packet() {
+            let packet = match packet {
+                ProxyPacket::Fragment(fragment) => {
+                    match reassembler.push(fragment, 4096).unwrap() {
+                        Some(data) => ProxyPacket::decode(data).unwrap(),
+                        None => continue,
+                    }
+                }
+                packet => packet,
+            };
+
+            packets.push(format!("{packet:?}"));
+        }
+
+        packets
+    }
+
+    #[test]
+    fn fragments_round_trip() {
+        let mut fragmenter = Fragmenter::new(100, 4096);
+        let mut reassembler = Reassembler::default();
+
+        // Small packets and everything that isn't binary data go straight through
+        assert!(fragmenter.push(binary(99, 0)).unwrap().is_some());
+        assert!(fragmenter
+            .push(ProxyPacket::Text("x".repeat(1000)))
+            .unwrap()
+            .is_some());
+
+        let first = binary(1000, 1).on_channel(2);
+        let second = binary(250, 2);
+        assert!(fragmenter.push(first.clone()).unwrap().is_none());
+        assert!(fragmenter.push(second.clone()).unwrap().is_none());
+
+        // The shorter message finishes first, since the two are on different channels and take turns
+        let packets = reassemble(&mut fragmenter, &mut reassembler);
+        assert_eq!(packets, [format!("{second:?}"), format!("{first:?}")]);
+        assert!(fragmenter.is_empty());
+    }
+
+    #[test]
+    fn channels_keep_their_order() {
+        let mut fragmenter = Fragmenter::new(100, 4096);
+        let mut reassembler = Reassembler::default();
+
+        let first = binary(1000, 1).on_channel(2);
+        let second = binary(250, 2).on_channel(2);
+        let small = binary(10, 3).on_channel(2);
+        let other = binary(10, 4);
+        assert!(fragmenter.push(first.clone()).unwrap().is_none());
+        assert!(fragmenter.push(second.clone()).unwrap().is_none());
+        assert!(fragmenter.push(small.clone()).unwrap().is_none());
+
+        // Other channels don't wait on it though
+        assert!(fragmenter.push(other).unwrap().is_some());
+
+        // Everything on one channel comes out in the order it went in, small packets included
+        let packets = reassemble(&mut fragmenter, &mut reassembler);
+        assert_eq!(
+            packets,
+            [
+                format!("{first:?}"),
+                format!("{second:?}"),
+                format!("{small:?}")
+            ]
+        );
+        assert!(fragmenter.is_empty());
+    }
+
+    #[test]
+    fn missing_fragments_drop_the_message() {
+        let mut fragmenter = Fragmenter::new(100, 4096);
+        let mut reassembler = Reassembler::default();
+
+        fragmenter.push(binary(300, 1)).unwrap();
+        fragmenter.next_packet();
+
+        let Some(ProxyPacket::Fragment(fragment)) = fragmenter.next_packet() else {
+            panic!("no fragment");
+        };
+        assert!(matches!(
+            reassembler.push(fragment, 4096),
+            Err(ProtoError::IncompleteMessage(0))
+        ));
+    }
+
+    #[test]
+    fn only_so_many_messages_are_sent_at_once() {
+        let mut fragmenter = Fragmenter::new(100, 4096);
+        let mut reassembler = Reassembler::default();
+
+        for message in 0..MAX_PARTIAL_MESSAGES * 2 {
+            fragmenter
+                .push(binary(250, message as u8).on_channel(message as u8))
+                .unwrap();
+        }
+
+        // The other end would give up on the oldest message if more were started than it can hold
+        let mut started = std::collections::HashSet::new();
+        let mut finished = 0;
+        while let Some(ProxyPacket::Fragment(fragment)) = fragmenter.next_packet() {
+            started.insert(fragment.message);
+            assert!(started.len() - finished <= MAX_PARTIAL_MESSAGES);
+
+            if reassembler.push(fragment, 4096).unwrap().is_some() {
+                finished += 1;
+            }
+        }
+        assert_eq!(finished, MAX_PARTIAL_MESSAGES * 2);
+    }
+
+    #[test]
+    fn messages_too_big_are_refused() {
+        let mut fragmenter = Fragmenter::new(100, 1024);
+        assert!(matches!(
+            fragmenter.push(binary(2000, 0)),
+            Err(ProtoError::FrameTooLarge { .. })
+        ));
+        assert!(fragmenter.is_empty());
+    }
+
+    #[test]
+    fn fragment_size_fits_in_a_frame() {
+        // Room is left for everything a fragment can be wrapped in
+        let fragmenter = Fragmenter::new(usize::MAX, 1024);
+        assert_eq!(fragmenter.fragment_size, 1024 - FRAGMENT_OVERHEAD);
+
+        let mut payload = BytesMut::new();
+        let fragment = Fragment {
+            message: 0,
+            index: 0,
+            last: false,
+            data: Bytes::from(vec![0; fragmenter.fragment_size]),
+        };
+        ProxyPacket::Timestamped(
+            0,
+            Box::new(ProxyPacket::Sequenced(
+                0,
+                Box::new(ProxyPacket::Fragment(fragment)),
+            )),
+        )
+        .encode(&mut payload)
+        .unwrap();
+        assert_eq!(payload.len() + AUTH_COUNTER_LEN + AUTH_TAG_LEN, 1024);
+
+        let fragmenter = Fragmenter::new(1, 1 << 24);
+        assert!(fragmenter.fragment_size * u16::MAX as usize >= 1 << 24);
+    }
+}
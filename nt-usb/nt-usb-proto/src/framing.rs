@@ -0,0 +1,630 @@
+use std::io::{self, ErrorKind, IoSlice, Read, Write};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use crc::{Crc, CRC_16_IBM_3740, CRC_32_ISO_HDLC};
+use serde::Deserialize;
+
+use crate::{ProtoError, Result};
+
+/// Marker written at the start of every length prefixed frame so a reader can find the next frame after line noise
+pub const FRAME_SYNC: [u8; 2] = [0x30, 0x03];
+
+/// Length of the frame header on the wire (sync marker, payload length and header checksum)
+pub const FRAME_HEADER_LEN: usize = FRAME_SYNC.len() + 4 + 2;
+
+/// Length of the checksum trailing every frame payload
+pub const FRAME_TRAILER_LEN: usize = 4;
+
+/// Byte terminating every COBS frame (it never appears inside an encoded frame)
+pub const COBS_DELIMITER: u8 = 0x00;
+
+/// Checksum covering the sync marker and payload length, so a corrupted length is never trusted
+const HEADER_CRC: Crc<u16> = Crc::<u16>::new(&CRC_16_IBM_3740);
+
+/// Checksum covering the packet payload
+const PAYLOAD_CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// How frames are delimited on the wire
+///
+/// Both ends of a link have to be configured with the same framing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Framing {
+    /// A sync marker and checksummed length header, followed by the payload and its checksum
+    #[default]
+    LengthPrefixed,
+    /// The COBS encoded payload and its checksum, surrounded by `0x00` bytes
+    ///
+    /// Frames are self delimiting, so a reader can join mid-stream and lose at most the frame it joined in
+    Cobs,
+}
+
+/// Builds the frame header for a payload of `len` bytes
+fn encode_header(len: u32) -> [u8; FRAME_HEADER_LEN] {
+    let mut header = [0u8; FRAME_HEADER_LEN];
+
+    header[..2].copy_from_slice(&FRAME_SYNC);
+    header[2..6].copy_from_slice(&len.to_le_bytes());
+
+    let crc = HEADER_CRC.checksum(&header[..6]);
+    header[6..].copy_from_slice(&crc.to_le_bytes());
+
+    header
+}
+
+/// Returns the payload length stored in `header` if it is a valid frame header
+fn decode_header(header: &[u8; FRAME_HEADER_LEN]) -> Option<u32> {
+    if header[..2] != FRAME_SYNC {
+        return None;
+    }
+
+    let crc = u16::from_le_bytes([header[6], header[7]]);
+    if HEADER_CRC.checksum(&header[..6]) != crc {
+        return None;
+    }
+
+    Some(u32::from_le_bytes([
+        header[2], header[3], header[4], header[5],
+    ]))
+}
+
+/// Wraps `payload` in a frame and appends it to `dst`, ready to go out on the wire
+///
+/// A payload bigger than `max_frame_size` is refused with [`ProtoError::FrameTooLarge`]
+pub fn encode_frame(
+    payload: &[u8],
+    framing: Framing,
+    max_frame_size: u32,
+    dst: &mut BytesMut,
+) -> Result<()> {
+    let start = start_frame(dst, framing);
+    dst.extend_from_slice(payload);
+
+    finish_frame(dst, start, framing, max_frame_size)
+}
+
+/// Leaves room for a frame header at the end of `dst`, and returns where the frame starts
+///
+/// The payload gets written straight into `dst` after this, and [`finish_frame`] fills the header
+/// in around it, so it never has to be copied into place
+pub(crate) fn start_frame(dst: &mut BytesMut, framing: Framing) -> usize {
+    let start = dst.len();
+
+    if framing == Framing::LengthPrefixed {
+        dst.put_bytes(0, FRAME_HEADER_LEN);
+    }
+
+    start
+}
+
+/// Frames the payload written to `dst` since [`start_frame`] returned `start`
+///
+/// The frame is taken back out of `dst` if the payload turns out to be too big
+pub(crate) fn finish_frame(
+    dst: &mut BytesMut,
+    start: usize,
+    framing: Framing,
+    max_frame_size: u32,
+) -> Result<()> {
+    match framing {
+        Framing::LengthPrefixed => {
+            let payload = &dst[start + FRAME_HEADER_LEN..];
+            if let Err(e) = check_frame_size(payload.len(), max_frame_size) {
+                dst.truncate(start);
+                return Err(e);
+            }
+
+            // Fill in the sync marker, the payload length in LE, and the header checksum
+            let crc = PAYLOAD_CRC.checksum(payload);
+            let header = encode_header(payload.len() as u32);
+            dst[start..start + FRAME_HEADER_LEN].copy_from_slice(&header);
+
+            // Follow the payload with its checksum
+            dst.put_u32_le(crc);
+        }
+        Framing::Cobs => {
+            let mut raw = dst.split_off(start);
+            check_frame_size(raw.len(), max_frame_size)?;
+
+            let crc = PAYLOAD_CRC.checksum(&raw);
+            raw.put_u32_le(crc);
+
+            // Lead with a delimiter so any noise already on the line ends up in its own (dropped) frame
+            dst.reserve(cobs::max_encoding_length(raw.len()) + 2);
+            dst.put_u8(COBS_DELIMITER);
+
+            // Stuff out every zero byte, then terminate the frame with one
+            let encoded_start = dst.len();
+            dst.resize(encoded_start + cobs::max_encoding_length(raw.len()), 0);
+            let len = cobs::encode(&raw, &mut dst[encoded_start..]);
+            dst.truncate(encoded_start + len);
+            dst.put_u8(COBS_DELIMITER);
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes sure a payload of `len` bytes is allowed on the link
+pub(crate) fn check_frame_size(len: usize, max_frame_size: u32) -> Result<()> {
+    if len > max_frame_size as usize {
+        return Err(ProtoError::FrameTooLarge {
+            len,
+            max: max_frame_size as usize,
+        });
+    }
+
+    Ok(())
+}
+
+/// Longest a COBS frame carrying up to `max_frame_size` bytes of payload can be, without its delimiters
+fn max_cobs_frame_len(max_frame_size: u32) -> usize {
+    cobs::max_encoding_length(max_frame_size as usize + FRAME_TRAILER_LEN)
+}
+
+/// Wraps `payload` in a frame and writes it out
+///
+/// Length prefixed frames go out as one vectored write of the header, payload and checksum, so the
+/// payload isn't copied again just to put a header in front of it
+pub fn write_frame<W: Write + ?Sized>(
+    writer: &mut W,
+    payload: &[u8],
+    framing: Framing,
+    max_frame_size: u32,
+) -> Result<()> {
+    match framing {
+        Framing::LengthPrefixed => {
+            check_frame_size(payload.len(), max_frame_size)?;
+
+            let header = encode_header(payload.len() as u32);
+            let crc = PAYLOAD_CRC.checksum(payload).to_le_bytes();
+
+            let mut bufs = [
+                IoSlice::new(&header),
+                IoSlice::new(payload),
+                IoSlice::new(&crc),
+            ];
+            write_all_vectored(writer, &mut bufs)?;
+        }
+        // Stuffing copies the payload anyway, so write the whole frame in one go
+        Framing::Cobs => {
+            let mut frame = BytesMut::new();
+            encode_frame(payload, framing, max_frame_size, &mut frame)?;
+            writer.write_all(&frame)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes every byte in `bufs`, in as few writes as `writer` allows
+fn write_all_vectored<W: Write + ?Sized>(
+    writer: &mut W,
+    mut bufs: &mut [IoSlice<'_>],
+) -> io::Result<()> {
+    IoSlice::advance_slices(&mut bufs, 0);
+
+    while !bufs.is_empty() {
+        match writer.write_vectored(bufs) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    ErrorKind::WriteZero,
+                    "failed to write whole frame",
+                ))
+            }
+            Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Pulls frames out of a buffer that bytes from the wire keep getting appended to
+#[derive(Debug, Clone)]
+pub struct FrameDecoder {
+    framing: Framing,
+    /// Largest frame payload accepted
+    max_frame_size: u32,
+    /// Whether the rest of an oversized COBS frame is still being skipped over
+    discarding: bool,
+}
+
+impl FrameDecoder {
+    pub fn new(framing: Framing, max_frame_size: u32) -> Self {
+        FrameDecoder {
+            framing,
+            max_frame_size,
+            discarding: false,
+        }
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    pub fn max_frame_size(&self) -> u32 {
+        self.max_frame_size
+    }
+
+    pub fn set_max_frame_size(&mut self, max_frame_size: u32) {
+        self.max_frame_size = max_frame_size;
+    }
+
+    /// Pulls the payload of the next frame out of `buf`, dropping any garbage before it
+    ///
+    /// Returns `None` if `buf` doesn't hold a whole frame yet, leaving the start of it in `buf`. A frame
+    /// that arrived corrupted is taken out of `buf` and returned as [`ProtoError::ChecksumMismatch`], and
+    /// one bigger than the maximum frame size is skipped over without buffering the rest of it.
+    ///
+    /// The payload is split off `buf` rather than copied out of it
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Bytes>> {
+        match self.framing {
+            Framing::LengthPrefixed => decode_length_prefixed_frame(buf, self.max_frame_size),
+            Framing::Cobs => self.decode_cobs_frame(buf),
+        }
+    }
+
+    fn decode_cobs_frame(&mut self, buf: &mut BytesMut) -> Result<Option<Bytes>> {
+        let max_len = max_cobs_frame_len(self.max_frame_size);
+
+        loop {
+            // Wait for the delimiter ending the frame to arrive
+            let Some(end) = buf.iter().position(|byte| *byte == COBS_DELIMITER) else {
+                // Drop a frame as soon as it's too big, and the rest of it as it arrives
+                if self.discarding {
+                    buf.clear();
+                } else if buf.len() > max_len {
+                    let len = buf.len();
+                    buf.clear();
+                    self.discarding = true;
+
+                    return Err(ProtoError::FrameTooLarge {
+                        len,
+                        max: self.max_frame_size as usize,
+                    });
+                }
+
+                return Ok(None);
+            };
+            let mut encoded = buf.split_to(end + 1);
+            encoded.truncate(end);
+
+            // The end of a frame that was too big
+            if self.discarding {
+                self.discarding = false;
+                continue;
+            }
+
+            // Back to back delimiters are just the gap between two frames
+            if end > 0 {
+                return unwrap_cobs_frame(encoded, self.max_frame_size).map(Some);
+            }
+        }
+    }
+}
+
+fn decode_length_prefixed_frame(buf: &mut BytesMut, max_frame_size: u32) -> Result<Option<Bytes>> {
+    loop {
+        // Drop everything before the next sync marker, keeping a trailing half of one
+        let Some(start) = buf.windows(2).position(|window| window == FRAME_SYNC) else {
+            let keep = usize::from(buf.last() == Some(&FRAME_SYNC[0]));
+            buf.advance(buf.len() - keep);
+            return Ok(None);
+        };
+        buf.advance(start);
+
+        if buf.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+
+        // A sync marker without a valid header after it is just noise
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        header.copy_from_slice(&buf[..FRAME_HEADER_LEN]);
+        let Some(len) = decode_header(&header) else {
+            buf.advance(1);
+            continue;
+        };
+
+        // Never buffer up more than a frame is allowed to hold, so resync just past its sync marker
+        if let Err(e) = check_frame_size(len as usize, max_frame_size) {
+            buf.advance(1);
+            return Err(e);
+        }
+
+        // Wait for the rest of the frame to arrive
+        let payload_end = FRAME_HEADER_LEN + len as usize;
+        let frame_len = payload_end + FRAME_TRAILER_LEN;
+        if buf.len() < frame_len {
+            buf.reserve(frame_len - buf.len());
+            return Ok(None);
+        }
+
+        let mut crc = [0u8; FRAME_TRAILER_LEN];
+        crc.copy_from_slice(&buf[payload_end..frame_len]);
+
+        // Only a frame that made it across intact is worth decoding, otherwise resync just past its sync marker
+        if PAYLOAD_CRC.checksum(&buf[FRAME_HEADER_LEN..payload_end]) != u32::from_le_bytes(crc) {
+            buf.advance(1);
+            return Err(ProtoError::ChecksumMismatch);
+        }
+
+        let mut data = buf.split_to(frame_len);
+        data.advance(FRAME_HEADER_LEN);
+        data.truncate(len as usize);
+
+        return Ok(Some(data.freeze()));
+    }
+}
+
+/// Undoes the byte stuffing on a COBS frame in place, and returns its payload if it made it across intact
+fn unwrap_cobs_frame(mut data: BytesMut, max_frame_size: u32) -> Result<Bytes> {
+    if data.len() > max_cobs_frame_len(max_frame_size) {
+        return Err(ProtoError::FrameTooLarge {
+            len: data.len(),
+            max: max_frame_size as usize,
+        });
+    }
+
+    let Ok(len) = cobs::decode_in_place(&mut data) else {
+        return Err(ProtoError::ChecksumMismatch);
+    };
+    data.truncate(len);
+
+    if data.len() < FRAME_TRAILER_LEN {
+        return Err(ProtoError::ChecksumMismatch);
+    }
+
+    // Split off the checksum and make sure the payload made it across intact
+    let crc = data.split_off(data.len() - FRAME_TRAILER_LEN);
+    if PAYLOAD_CRC.checksum(&data) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+        return Err(ProtoError::ChecksumMismatch);
+    }
+    check_frame_size(data.len(), max_frame_size)?;
+
+    Ok(data.freeze())
+}
+
+/// Reads the next frame, and returns its payload
+///
+/// Garbage before the frame is skipped over, and a frame that arrived corrupted is returned as
+/// [`ProtoError::ChecksumMismatch`] so the caller can carry on with the next one. A frame bigger
+/// than `max_frame_size` is returned as [`ProtoError::FrameTooLarge`] before any of it is buffered.
+pub fn read_frame<R: Read + ?Sized>(
+    reader: &mut R,
+    framing: Framing,
+    max_frame_size: u32,
+) -> Result<Bytes> {
+    match framing {
+        Framing::LengthPrefixed => read_length_prefixed_frame(reader, max_frame_size),
+        Framing::Cobs => read_cobs_frame(reader, max_frame_size),
+    }
+}
+
+fn read_length_prefixed_frame<R: Read + ?Sized>(reader: &mut R, max_frame_size: u32) -> Result<Bytes> {
+    // Read a full header's worth of bytes
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    reader.read_exact(&mut header)?;
+
+    // Slide forward one byte at a time until the window holds a valid header
+    let len = loop {
+        if let Some(len) = decode_header(&header) {
+            break len;
+        }
+
+        header.copy_within(1.., 0);
+        reader.read_exact(&mut header[FRAME_HEADER_LEN - 1..])?;
+    };
+
+    // Don't trust the length enough to allocate it if it's over the limit, the next read will resync
+    check_frame_size(len as usize, max_frame_size)?;
+
+    // Read the rest of the packet (`len` bytes) and its checksum
+    let mut data = vec![0u8; len as usize];
+    reader.read_exact(&mut data)?;
+
+    let mut crc = [0u8; FRAME_TRAILER_LEN];
+    reader.read_exact(&mut crc)?;
+
+    // Only a frame that made it across intact is worth decoding
+    if PAYLOAD_CRC.checksum(&data) != u32::from_le_bytes(crc) {
+        return Err(ProtoError::ChecksumMismatch);
+    }
+
+    Ok(Bytes::from(data))
+}
+
+fn read_cobs_frame<R: Read + ?Sized>(reader: &mut R, max_frame_size: u32) -> Result<Bytes> {
+    let max_len = max_cobs_frame_len(max_frame_size);
+
+    loop {
+        // Read everything up to the next delimiter
+        let mut encoded = BytesMut::new();
+        loop {
+            let mut byte = [0u8; 1];
+            reader.read_exact(&mut byte)?;
+
+            if byte[0] == COBS_DELIMITER {
+                break;
+            }
+
+            encoded.put_u8(byte[0]);
+
+            // Give up on a frame that's already too big, and skip over the rest of it
+            if encoded.len() > max_len {
+                let mut len = encoded.len();
+                loop {
+                    reader.read_exact(&mut byte)?;
+                    if byte[0] == COBS_DELIMITER {
+                        break;
+                    }
+                    len += 1;
+                }
+
+                return Err(ProtoError::FrameTooLarge {
+                    len,
+                    max: max_frame_size as usize,
+                });
+            }
+        }
+
+        // Back to back delimiters are just the gap between two frames
+        if !encoded.is_empty() {
+            return unwrap_cobs_frame(encoded, max_frame_size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const FRAMINGS: [Framing; 2] = [Framing::LengthPrefixed, Framing::Cobs];
+
+    /// Reads frames until one comes back that isn't a recoverable error
+    fn next_frame(reader: &mut Cursor<Vec<u8>>, framing: Framing) -> Result<Bytes> {
+        loop {
+            match read_frame(reader, framing, 64) {
+                Err(e) if e.is_recoverable() => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Frames `payloads` with line noise before the first, and a corrupted frame after it
+    fn noisy_stream(framing: Framing, payloads: &[&[u8]]) -> Vec<u8> {
+        let mut stream = vec![0x30, 0x00, 0x30, 0x03, 0x09, 0xff];
+
+        for (i, payload) in payloads.iter().enumerate() {
+            write_frame(&mut stream, payload, framing, 64).unwrap();
+
+            if i == 0 {
+                let mut corrupted = Vec::new();
+                write_frame(&mut corrupted, b"corrupted", framing, 64).unwrap();
+                let len = corrupted.len();
+                corrupted[len - 3] ^= 0x40;
+                stream.extend(corrupted);
+            }
+        }
+
+        stream
+    }
+
+    #[test]
+    fn frame_headers_round_trip() {
+        for len in [0, 1, 300, u32::MAX] {
+            assert_eq!(decode_header(&encode_header(len)), Some(len));
+        }
+    }
+
+    #[test]
+    fn corrupted_frame_headers_are_refused() {
+        let header = encode_header(300);
+
+        // Line noise that happens to look like the sync marker still needs the checksum to match
+        for i in 0..FRAME_HEADER_LEN {
+            let mut corrupted = header;
+            corrupted[i] ^= 0x40;
+            assert_eq!(decode_header(&corrupted), None, "byte {}", i);
+        }
+    }
+
+    #[test]
+    fn frames_round_trip() {
+        for framing in FRAMINGS {
+            let mut stream = Vec::new();
+            for payload in [&b"hello"[..], b"", b"\0\0zero\0", &[0xff; 64]] {
+                write_frame(&mut stream, payload, framing, 64).unwrap();
+            }
+
+            let mut reader = Cursor::new(stream);
+            assert_eq!(read_frame(&mut reader, framing, 64).unwrap(), &b"hello"[..]);
+            assert_eq!(read_frame(&mut reader, framing, 64).unwrap(), &b""[..]);
+            assert_eq!(read_frame(&mut reader, framing, 64).unwrap(), &b"\0\0zero\0"[..]);
+            assert_eq!(read_frame(&mut reader, framing, 64).unwrap(), &[0xff; 64][..]);
+            assert!(matches!(read_frame(&mut reader, framing, 64), Err(ProtoError::Io(_))));
+        }
+    }
+
+    #[test]
+    fn read_frame_resyncs_after_noise() {
+        for framing in FRAMINGS {
+            let stream = noisy_stream(framing, &[b"first", b"second"]);
+            let mut reader = Cursor::new(stream);
+
+            assert_eq!(next_frame(&mut reader, framing).unwrap(), &b"first"[..]);
+            assert!(matches!(
+                read_frame(&mut reader, framing, 64),
+                Err(ProtoError::ChecksumMismatch)
+            ));
+            assert_eq!(next_frame(&mut reader, framing).unwrap(), &b"second"[..]);
+        }
+    }
+
+    #[test]
+    fn oversized_frames_are_refused() {
+        for framing in FRAMINGS {
+            let mut stream = Vec::new();
+            assert!(matches!(
+                write_frame(&mut stream, &[1; 65], framing, 64),
+                Err(ProtoError::FrameTooLarge { len: 65, max: 64 })
+            ));
+            assert!(stream.is_empty());
+
+            // A frame from an end with a bigger limit is skipped over, and the next one still read
+            write_frame(&mut stream, &[1; 200], framing, 1024).unwrap();
+            write_frame(&mut stream, b"after", framing, 64).unwrap();
+
+            let mut reader = Cursor::new(stream);
+            assert!(matches!(
+                read_frame(&mut reader, framing, 64),
+                Err(ProtoError::FrameTooLarge { .. })
+            ));
+            assert_eq!(next_frame(&mut reader, framing).unwrap(), &b"after"[..]);
+        }
+    }
+
+    #[test]
+    fn decoder_handles_partial_frames() {
+        for framing in FRAMINGS {
+            let stream = noisy_stream(framing, &[b"first", b"\0second\0"]);
+            let mut decoder = FrameDecoder::new(framing, 64);
+            let mut buf = BytesMut::new();
+            let mut frames = Vec::new();
+
+            // Feed it a byte at a time, as slowly as the wire ever would
+            for byte in stream {
+                buf.put_u8(byte);
+                loop {
+                    match decoder.decode(&mut buf) {
+                        Ok(Some(frame)) => frames.push(frame),
+                        Ok(None) => break,
+                        Err(e) => assert!(e.is_recoverable(), "{e:?}"),
+                    }
+                }
+            }
+
+            assert_eq!(frames, [&b"first"[..], &b"\0second\0"[..]], "{framing:?}");
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn decoder_skips_oversized_frames() {
+        for framing in FRAMINGS {
+            let mut buf = BytesMut::new();
+            encode_frame(&[7; 300], framing, 1024, &mut buf).unwrap();
+            encode_frame(b"after", framing, 64, &mut buf).unwrap();
+
+            let mut decoder = FrameDecoder::new(framing, 64);
+            assert!(matches!(
+                decoder.decode(&mut buf),
+                Err(ProtoError::FrameTooLarge { .. })
+            ));
+            assert_eq!(decoder.decode(&mut buf).unwrap().unwrap(), &b"after"[..]);
+        }
+    }
+}
@@ -0,0 +1,483 @@
+use std::io::{Cursor, Error, ErrorKind, Read};
+use std::ops::BitOr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::{BufMut, BytesMut};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+
+use crate::{LinkStats, ProtoError, ProxyPacket, Result};
+
+/// Version of the USB link protocol spoken by this build
+///
+/// Bump this whenever the framing or the encoding of an existing packet changes
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Oldest protocol version this build can still talk to
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Largest frame payload accepted unless configured otherwise, advertised to the other end of the link
+pub const DEFAULT_MAX_FRAME_SIZE: u32 = 64 * 1024;
+
+/// How long to wait for the other end to answer before sending another `Hello`
+pub const HELLO_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Frames in a row that can fail authentication during the handshake before the other end is taken to
+/// have a different key, rather than still be starting up
+const MAX_HANDSHAKE_AUTH_FAILURES: u64 = 3;
+
+/// Optional protocol features an end of the link supports
+///
+/// New packet types must only be sent once the other end has advertised the matching capability,
+/// so older consoles keep working while newer builds roll out
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Capabilities(pub u32);
+
+impl Capabilities {
+    /// `Ping`/`Pong` heartbeats, and dead link detection
+    pub const HEARTBEAT: Capabilities = Capabilities(1 << 0);
+    /// `Sequenced`/`Ack` packets for reliable delivery
+    pub const RELIABLE: Capabilities = Capabilities(1 << 1);
+    /// `Channel` packets for more than one logical channel over the link
+    pub const MULTIPLEX: Capabilities = Capabilities(1 << 2);
+    /// Deflate compressed `Text` and `Binary` packets
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 3);
+    /// `WsPing`/`WsPong` packets carrying WS ping and pong frames
+    pub const WS_PING: Capabilities = Capabilities(1 << 4);
+    /// `Open` packets choosing which NT server session each channel connects to
+    pub const OPEN: Capabilities = Capabilities(1 << 5);
+    /// `Fragment` packets splitting large `Binary` packets up so other packets can go in between
+    pub const FRAGMENTATION: Capabilities = Capabilities(1 << 6);
+    /// `SwitchBaud`/`SwitchBaudAck` packets moving the link up to a faster baud rate after the handshake
+    pub const BAUD_SWITCH: Capabilities = Capabilities(1 << 7);
+    /// `Credit`/`CreditProbe` packets limiting how far ahead of the receiver a sender can get
+    ///
+    /// Only used alongside `RELIABLE`, since a data packet lost on the way would take its credit with it
+    pub const FLOW_CONTROL: Capabilities = Capabilities(1 << 8);
+    /// `Timestamped` frames and `TimeRequest`/`TimeReply` packets, for measuring how long the link takes
+    pub const TIMESTAMPS: Capabilities = Capabilities(1 << 9);
+    /// `Batch` packets carrying several small packets in one frame
+    pub const BATCHING: Capabilities = Capabilities(1 << 10);
+    /// `ControlRequest`/`ControlResponse` packets running commands on the other end, e.g. reloading its config
+    pub const CONTROL: Capabilities = Capabilities(1 << 11);
+
+    pub const fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Capabilities supported by both `self` and `other`
+    pub const fn intersection(self, other: Capabilities) -> Self {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+/// Everything one end of the link tells the other about itself in `Hello` and `HelloAck` packets
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkInfo {
+    pub protocol_version: u16,
+    /// Oldest protocol version the sender can still talk to
+    pub min_protocol_version: u16,
+    /// Name and version of the program on this end, purely for diagnostics
+    pub build_version: String,
+    pub max_frame_size: u32,
+    pub capabilities: Capabilities,
+    /// Baud rates the sender's port can run at, in any order
+    pub baud_rates: Vec<u32>,
+    /// Picked afresh for every connection, so a `Hello` from a restarted end can be told apart from
+    /// one that was resent during the handshake. Builds from before sessions send 0
+    pub session: u32,
+}
+
+/// What both ends of the link agreed on after a successful handshake
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinkParams {
+    pub protocol_version: u16,
+    /// Largest frame payload either end may send
+    pub max_frame_size: u32,
+    pub capabilities: Capabilities,
+    /// Fastest baud rate both ends' ports can run at, if both ends can switch baud rates
+    pub baud_rate: Option<u32>,
+    /// What the other end told us about itself
+    pub peer: LinkInfo,
+}
+
+impl LinkInfo {
+    /// Describes this build, with `build_version` naming the program (e.g. `nt-usb-proxy 0.1.0`)
+    pub fn new(
+        build_version: impl Into<String>,
+        capabilities: Capabilities,
+        max_frame_size: u32,
+    ) -> Self {
+        LinkInfo {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+            build_version: build_version.into(),
+            max_frame_size,
+            capabilities,
+            baud_rates: Vec::new(),
+            session: new_session(),
+        }
+    }
+
+    /// Advertises the baud rates this end's port can run at, for `Capabilities::BAUD_SWITCH`
+    pub fn with_baud_rates(mut self, baud_rates: Vec<u32>) -> Self {
+        self.baud_rates = baud_rates;
+        self
+    }
+
+    pub(crate) fn encode(&self, res: &mut BytesMut) {
+        res.extend_from_slice(&self.protocol_version.to_le_bytes());
+        res.extend_from_slice(&self.min_protocol_version.to_le_bytes());
+        res.extend_from_slice(&self.max_frame_size.to_le_bytes());
+        res.extend_from_slice(&self.capabilities.0.to_le_bytes());
+
+        // The build version is length prefixed so newer builds can append fields after it
+        let build_version = self.build_version.as_bytes();
+        res.extend_from_slice(&(build_version.len() as u16).to_le_bytes());
+        res.extend_from_slice(build_version);
+
+        // Appended after the build version, so older builds skip over it
+        res.put_u8(self.baud_rates.len().min(u8::MAX as usize) as u8);
+        for baud_rate in self.baud_rates.iter().take(u8::MAX as usize) {
+            res.put_u32_le(*baud_rate);
+        }
+
+        res.put_u32_le(self.session);
+    }
+
+    /// Returns `None` if `bytes` ends before all of the fields have been read
+    pub(crate) fn decode(bytes: &[u8]) -> Option<LinkInfo> {
+        let mut cursor = Cursor::new(bytes);
+
+        let mut protocol_version = [0u8; 2];
+        cursor.read_exact(&mut protocol_version).ok()?;
+
+        let mut min_protocol_version = [0u8; 2];
+        cursor.read_exact(&mut min_protocol_version).ok()?;
+
+        let mut max_frame_size = [0u8; 4];
+        cursor.read_exact(&mut max_frame_size).ok()?;
+
+        let mut capabilities = [0u8; 4];
+        cursor.read_exact(&mut capabilities).ok()?;
+
+        let mut build_version_len = [0u8; 2];
+        cursor.read_exact(&mut build_version_len).ok()?;
+
+        let mut build_version = vec![0u8; u16::from_le_bytes(build_version_len) as usize];
+        cursor.read_exact(&mut build_version).ok()?;
+
+        // Older builds stop after the build version, and don't advertise any baud rates
+        let mut baud_rates = Vec::new();
+        let mut baud_rate_count = [0u8; 1];
+        if cursor.read_exact(&mut baud_rate_count).is_ok() {
+            for _ in 0..baud_rate_count[0] {
+                let mut baud_rate = [0u8; 4];
+                cursor.read_exact(&mut baud_rate).ok()?;
+                baud_rates.push(u32::from_le_bytes(baud_rate));
+            }
+        }
+
+        // Builds from before sessions stop after the baud rates
+        let mut session = [0u8; 4];
+        let session = match cursor.read_exact(&mut session) {
+            Ok(()) => u32::from_le_bytes(session),
+            Err(_) => 0,
+        };
+
+        // Anything left over was added by a newer build, and is safe to ignore
+        Some(LinkInfo {
+            protocol_version: u16::from_le_bytes(protocol_version),
+            min_protocol_version: u16::from_le_bytes(min_protocol_version),
+            build_version: String::from_utf8_lossy(&build_version).into_owned(),
+            max_frame_size: u32::from_le_bytes(max_frame_size),
+            capabilities: Capabilities(u32::from_le_bytes(capabilities)),
+            baud_rates,
+            session,
+        })
+    }
+
+    /// Works out what both ends can agree on, or why they can't talk to each other at all
+    ///
+    /// Both ends come to the same conclusion, so a refused link is reported on both sides
+    pub fn negotiate(&self, peer: &LinkInfo) -> Result<LinkParams> {
+        // Speak the newest version both ends know, as long as neither end has dropped support for it
+        let protocol_version = self.protocol_version.min(peer.protocol_version);
+        let min_protocol_version = self.min_protocol_version.max(peer.min_protocol_version);
+
+        if protocol_version < min_protocol_version {
+            return Err(ProtoError::VersionMismatch {
+                local: Box::new(self.clone()),
+                peer: Box::new(peer.clone()),
+            });
+        }
+
+        let capabilities = self.capabilities.intersection(peer.capabilities);
+
+        // Both ends pick the same rate, since it only depends on what both of them can do
+        let baud_rate = capabilities
+            .contains(Capabilities::BAUD_SWITCH)
+            .then(|| {
+                self.baud_rates
+                    .iter()
+                    .filter(|baud_rate| peer.baud_rates.contains(baud_rate))
+                    .max()
+                    .copied()
+            })
+            .flatten();
+
+        Ok(LinkParams {
+            protocol_version,
+            max_frame_size: self.max_frame_size.min(peer.max_frame_size),
+            capabilities,
+            baud_rate,
+            peer: peer.clone(),
+        })
+    }
+}
+
+/// Never 0, which is left for builds from before sessions
+fn new_session() -> u32 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_nanos() as u32).max(1)
+}
+
+/// Exchanges `Hello`/`HelloAck` packets with the other end of a freshly opened link
+///
+/// Both ends run the same exchange, so it doesn't matter which one opens the port first. A `Hello` is
+/// resent every [`HELLO_INTERVAL`] until the other end shows up, and anything else that arrives in the
+/// meantime is left over from an old session and gets dropped.
+///
+/// Gives up with [`ProtoError::KeyMismatch`] if nothing but frames failing authentication arrive, going
+/// by the counts in `stats` that the link's codec keeps, since no `Hello` would ever get through.
+pub async fn perform_handshake<S>(
+    link: &mut S,
+    local: &LinkInfo,
+    stats: &LinkStats,
+) -> Result<LinkParams>
+where
+    S: Stream<Item = Result<ProxyPacket>> + Sink<ProxyPacket, Error = ProtoError> + Unpin,
+{
+    link.send(ProxyPacket::Hello(local.clone())).await?;
+
+    // Auth failures from before the last frame that got through
+    let mut auth_failures = stats.snapshot().auth_failures;
+
+    let peer = loop {
+        match tokio::time::timeout(HELLO_INTERVAL, link.next()).await {
+            // The other end started the exchange, so answer it
+            Ok(Some(Ok(ProxyPacket::Hello(peer)))) => {
+                link.send(ProxyPacket::HelloAck(local.clone())).await?;
+                break peer;
+            }
+            // The other end answered our `Hello`
+            Ok(Some(Ok(ProxyPacket::HelloAck(peer)))) => break peer,
+            // Left over from an old session, but it shows the key matches
+            Ok(Some(Ok(_))) => auth_failures = stats.snapshot().auth_failures,
+            Ok(Some(Err(e))) => return Err(e),
+            Ok(None) => {
+                return Err(ProtoError::Io(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "USB link closed during the handshake",
+                )))
+            }
+            // Nobody answered yet, so try again, unless there's no chance of them ever getting through
+            Err(_) => {
+                if stats.snapshot().auth_failures - auth_failures >= MAX_HANDSHAKE_AUTH_FAILURES {
+                    return Err(ProtoError::KeyMismatch);
+                }
+                link.send(ProxyPacket::Hello(local.clone())).await?;
+            }
+        }
+    };
+
+    local.negotiate(&peer)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+    use super::*;
+
+    /// One end of an in-memory link, standing in for a `Framed` serial port
+    pub(crate) struct TestLink {
+        tx: UnboundedSender<ProxyPacket>,
+        rx: UnboundedReceiver<ProxyPacket>,
+        /// Decides whether each packet sent makes it to the other end
+        pub(crate) deliver: Box<dyn FnMut(&ProxyPacket) -> bool + Send>,
+    }
+
+    /// Connects two ends of a link that lose nothing
+    pub(crate) fn link_pair() -> (TestLink, TestLink) {
+        let (a_tx, b_rx) = unbounded();
+        let (b_tx, a_rx) = unbounded();
+
+        let end = |tx, rx| TestLink {
+            tx,
+            rx,
+            deliver: Box::new(|_| true),
+        };
+        (end(a_tx, a_rx), end(b_tx, b_rx))
+    }
+
+    impl Stream for TestLink {
+        type Item = Result<ProxyPacket>;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            self.rx.poll_next_unpin(cx).map(|packet| packet.map(Ok))
+        }
+    }
+
+    impl Sink<ProxyPacket> for TestLink {
+        type Error = ProtoError;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, packet: ProxyPacket) -> Result<()> {
+            if (self.deliver)(&packet) {
+                // The other end hanging up is the same as the packet getting lost
+                let _ = self.tx.unbounded_send(packet);
+            }
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn info(capabilities: Capabilities, max_frame_size: u32) -> LinkInfo {
+        LinkInfo::new("test 1.0", capabilities, max_frame_size)
+    }
+
+    #[test]
+    fn link_info_round_trips() {
+        let info = info(Capabilities::RELIABLE, 1024).with_baud_rates(vec![115_200, 921_600]);
+        let mut buf = BytesMut::new();
+        info.encode(&mut buf);
+        assert_eq!(LinkInfo::decode(&buf), Some(info.clone()));
+
+        // Builds from before sessions stop short of the session
+        let old = LinkInfo::decode(&buf[..buf.len() - 4]).unwrap();
+        assert_eq!(old.session, 0);
+        assert_eq!(old.baud_rates, info.baud_rates);
+
+        // Builds from before baud switching just stop short of the baud rates
+        let old = LinkInfo::decode(&buf[..buf.len() - 13]).unwrap();
+        assert!(old.baud_rates.is_empty());
+        assert_eq!(old.capabilities, info.capabilities);
+
+        // Newer builds can add more on the end
+        buf.extend_from_slice(b"more");
+        assert_eq!(LinkInfo::decode(&buf), Some(info));
+    }
+
+    #[test]
+    fn negotiate_picks_what_both_support() {
+        let local = info(Capabilities::HEARTBEAT | Capabilities::RELIABLE, 4096)
+            .with_baud_rates(vec![115_200, 460_800, 921_600]);
+        let peer = info(Capabilities::RELIABLE | Capabilities::COMPRESSION, 1024)
+            .with_baud_rates(vec![9600, 460_800]);
+
+        let params = local.negotiate(&peer).unwrap();
+        assert_eq!(params.capabilities, Capabilities::RELIABLE);
+        assert_eq!(params.max_frame_size, 1024);
+        assert_eq!(params.peer, peer);
+
+        // Both ends come to the same conclusion
+        let reverse = peer.negotiate(&local).unwrap();
+        assert_eq!(reverse.capabilities, params.capabilities);
+        assert_eq!(reverse.max_frame_size, params.max_frame_size);
+
+        // Baud rates only count once both ends can switch
+        assert_eq!(params.baud_rate, None);
+        let local = LinkInfo {
+            capabilities: Capabilities::BAUD_SWITCH,
+            ..local
+        };
+        let peer = LinkInfo {
+            capabilities: Capabilities::BAUD_SWITCH,
+            ..peer
+        };
+        assert_eq!(local.negotiate(&peer).unwrap().baud_rate, Some(460_800));
+    }
+
+    #[test]
+    fn negotiate_refuses_incompatible_versions() {
+        let local = info(Capabilities::empty(), 1024);
+        let old = LinkInfo {
+            protocol_version: 0,
+            min_protocol_version: 0,
+            ..local.clone()
+        };
+
+        assert!(matches!(
+            local.negotiate(&old),
+            Err(ProtoError::VersionMismatch { .. })
+        ));
+        assert!(matches!(
+            old.negotiate(&local),
+            Err(ProtoError::VersionMismatch { .. })
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_survives_a_lost_hello() {
+        let (mut a, mut b) = link_pair();
+        let mut lost = false;
+        a.deliver = Box::new(move |_| std::mem::replace(&mut lost, true));
+
+        let a_info = info(Capabilities::RELIABLE, 4096);
+        let b_info = info(Capabilities::RELIABLE | Capabilities::HEARTBEAT, 1024);
+        let stats = LinkStats::default();
+        let (a_params, b_params) = tokio::join!(
+            perform_handshake(&mut a, &a_info, &stats),
+            perform_handshake(&mut b, &b_info, &stats)
+        );
+
+        let (a_params, b_params) = (a_params.unwrap(), b_params.unwrap());
+        assert_eq!(a_params.peer, b_info);
+        assert_eq!(b_params.peer, a_info);
+        assert_eq!(a_params.capabilities, b_params.capabilities);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn handshake_gives_up_on_another_key() {
+        let (mut a, _b) = link_pair();
+        let stats = LinkStats::default();
+        let a_info = info(Capabilities::RELIABLE, 4096);
+
+        // What the codec counts when the other end's `Hello`s are signed with a different key
+        let other_key = async {
+            for _ in 0..MAX_HANDSHAKE_AUTH_FAILURES {
+                stats.record_error(&ProtoError::AuthenticationFailed);
+            }
+        };
+
+        let (params, _) = tokio::join!(perform_handshake(&mut a, &a_info, &stats), other_key);
+        assert!(matches!(params, Err(ProtoError::KeyMismatch)));
+    }
+}
@@ -1,12 +1,133 @@
-use std::io::{Cursor, Error, ErrorKind, Read, Result, Write};
+use std::io::{self, ErrorKind, Read, Write};
+use std::time::Duration;
 
-use serialport::SerialPort;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
 
-#[derive(Debug)]
+mod auth;
+mod baud;
+mod capture;
+mod clock;
+mod coalesce;
+mod codec;
+mod compression;
+mod control;
+mod error;
+mod flow;
+mod fragment;
+mod framing;
+mod handshake;
+mod link;
+mod open;
+mod priority;
+mod reliable;
+mod stats;
+
+pub use auth::{FrameAuth, AUTH_COUNTER_LEN, AUTH_TAG_LEN};
+pub use baud::{
+    switch_baud_rate, BaudRole, BAUD_SWITCH_SETTLE, BAUD_SWITCH_TIMEOUT, DEFAULT_BAUD_RATE,
+    DEFAULT_BAUD_RATES,
+};
+pub use capture::{
+    capture_path, spawn_capture, CaptureFormat, CaptureReader, CaptureRecord, CaptureTap,
+    CaptureWriter, Direction, CAPTURE_MAGIC, CAPTURE_VERSION, PCAPNG_LINK_TYPE,
+};
+pub use clock::{
+    monotonic_us, ClockEstimate, ClockSync, CLOCK_SYNC_INTERVAL, CLOCK_SYNC_SAMPLES,
+};
+pub use coalesce::{
+    Coalesced, Coalescer, BATCH_HEADER_LEN, DEFAULT_COALESCE_WINDOW, MAX_BATCH_LEN,
+    MAX_COALESCED_PACKET_LEN,
+};
+pub use codec::ProxyCodec;
+pub use compression::DEFAULT_COMPRESSION_THRESHOLD;
+pub use control::{ControlCommand, ControlResponse, LogLevel, PendingCommands, CONTROL_TIMEOUT};
+pub use error::{ProtoError, Result};
+pub use flow::{
+    CreditGate, DeliveryReceipt, ReceiveCredits, SendCredits, CREDIT_PROBE_INTERVAL,
+    DEFAULT_CREDIT_WINDOW,
+};
+pub use fragment::{Fragment, Fragmenter, DEFAULT_FRAGMENT_SIZE, FRAGMENT_HEADER_LEN};
+pub use framing::{
+    encode_frame, read_frame, write_frame, FrameDecoder, Framing, COBS_DELIMITER, FRAME_HEADER_LEN,
+    FRAME_SYNC, FRAME_TRAILER_LEN,
+};
+pub use handshake::{
+    perform_handshake, Capabilities, LinkInfo, LinkParams, DEFAULT_MAX_FRAME_SIZE, HELLO_INTERVAL,
+    MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+pub use link::{Connection, LinkConfig, LinkDown, Received};
+pub use open::{OpenRequest, NT4_SUBPROTOCOL};
+pub use priority::{Priority, PriorityQueue, MAX_CONTROL_BINARY_LEN};
+pub use reliable::{ReliableLink, UnackedGate, DEFAULT_RETRANSMIT_TIMEOUT, MAX_UNACKED_PACKETS};
+pub use stats::{write_stats_file, LinkStats, LinkStatsSnapshot};
+
+/// How often each end sends a `Ping` once heartbeats have been negotiated
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long the link may stay completely silent before the other end is declared dead
+pub const DEFAULT_LINK_TIMEOUT: Duration = Duration::from_millis(1500);
+
+/// Why a WS connection was closed, as sent in its close frame
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CloseReason {
+    /// WS close code (e.g. 1000 for a normal closure)
+    pub code: u16,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone)]
 pub enum ProxyPacket {
     Text(String),
-    Binary(Vec<u8>),
-    Close,
+    Binary(Bytes),
+    /// The WS connection was closed, with the code and reason from its close frame if it had one
+    Close(Option<CloseReason>),
+    /// Sent by both ends when the port opens, describing the sender
+    Hello(LinkInfo),
+    /// Answers a `Hello`, describing the sender
+    HelloAck(LinkInfo),
+    /// Link heartbeat, answered with a `Pong` carrying the same sequence number
+    Ping(u64),
+    /// Answers a `Ping`
+    Pong(u64),
+    /// Wraps a data packet, or one `Fragment` of one, with its sequence number when reliable delivery is on
+    Sequenced(u32, Box<ProxyPacket>),
+    /// Acknowledges every `Sequenced` packet before the given sequence number
+    Ack(u32),
+    /// Routes a data packet to one of several logical channels sharing the link
+    ///
+    /// Data packets that aren't wrapped belong to channel 0
+    Channel(u8, Box<ProxyPacket>),
+    /// A WS ping frame, with its payload
+    WsPing(Bytes),
+    /// A WS pong frame, with its payload
+    WsPong(Bytes),
+    /// Asks the proxy to (re)connect the channel's WS to the NT server
+    Open(OpenRequest),
+    /// One piece of a packet too big to send in one go, put back together before it's read
+    Fragment(Fragment),
+    /// Asks the other end to move the port to a new baud rate, straight after the handshake
+    SwitchBaud(u32),
+    /// Answers a `SwitchBaud`, just before the sender moves to the new baud rate
+    SwitchBaudAck(u32),
+    /// Lets the other end send data packets until it has sent this many in total, since the link came up
+    Credit(u32),
+    /// Asks the other end to send a `Credit` again, after going without for a while
+    CreditProbe,
+    /// Asks the other end for the time, carrying the sender's [`monotonic_us`] when it was sent
+    TimeRequest(u64),
+    /// Answers a `TimeRequest` with the time it carried, then the sender's [`monotonic_us`]
+    TimeReply(u64, u64),
+    /// Wraps a packet with the sender's [`monotonic_us`] from just before it went out
+    Timestamped(u64, Box<ProxyPacket>),
+    /// Several small packets sharing a frame, each encoded behind its length as a little endian `u16`
+    ///
+    /// Put together by a [`Coalescer`], and split up again before it's read
+    Batch(Bytes),
+    /// Asks the other end to run a [`ControlCommand`], carried as JSON under an ID the answer comes back with
+    ControlRequest(u32, Bytes),
+    /// Answers the `ControlRequest` with the same ID with a [`ControlResponse`], carried as JSON
+    ControlResponse(u32, Bytes),
 }
 
 impl ProxyPacket {
@@ -14,121 +135,507 @@ impl ProxyPacket {
         match self {
             ProxyPacket::Text(_) => 0,
             ProxyPacket::Binary(_) => 1,
-            ProxyPacket::Close => 2,
+            ProxyPacket::Close(_) => 2,
+            ProxyPacket::Hello(_) => 3,
+            ProxyPacket::HelloAck(_) => 4,
+            ProxyPacket::Ping(_) => 5,
+            ProxyPacket::Pong(_) => 6,
+            ProxyPacket::Sequenced(..) => 7,
+            ProxyPacket::Ack(_) => 8,
+            ProxyPacket::Channel(..) => 9,
+            // 10 marks compressed packets
+            ProxyPacket::WsPing(_) => 11,
+            ProxyPacket::WsPong(_) => 12,
+            ProxyPacket::Open(_) => 13,
+            ProxyPacket::Fragment(_) => 14,
+            ProxyPacket::SwitchBaud(_) => 15,
+            ProxyPacket::SwitchBaudAck(_) => 16,
+            ProxyPacket::Credit(_) => 17,
+            ProxyPacket::CreditProbe => 18,
+            ProxyPacket::TimeRequest(_) => 19,
+            ProxyPacket::TimeReply(..) => 20,
+            ProxyPacket::Timestamped(..) => 21,
+            ProxyPacket::Batch(_) => 22,
+            ProxyPacket::ControlRequest(..) => 23,
+            ProxyPacket::ControlResponse(..) => 24,
+        }
+    }
+
+    /// Tags a data packet with the logical channel it belongs to
+    pub fn on_channel(self, channel: u8) -> ProxyPacket {
+        match channel {
+            0 => self,
+            _ => ProxyPacket::Channel(channel, Box::new(self)),
+        }
+    }
+
+    /// Splits a data packet into the logical channel it belongs to and the packet itself
+    pub fn into_channel(self) -> (u8, ProxyPacket) {
+        match self {
+            ProxyPacket::Channel(channel, packet) => (channel, *packet),
+            packet => (0, packet),
         }
     }
 
-    pub fn encode(&self) -> Result<Vec<u8>> {
-        let mut res = Vec::new();
+    /// Returns true for packets that only exist to run the USB link, rather than carrying WS data
+    pub fn is_link_control(&self) -> bool {
+        !matches!(
+            self,
+            ProxyPacket::Text(_)
+                | ProxyPacket::Binary(_)
+                | ProxyPacket::Close(_)
+                | ProxyPacket::Channel(..)
+                | ProxyPacket::WsPing(_)
+                | ProxyPacket::WsPong(_)
+                | ProxyPacket::Open(_)
+        )
+    }
 
+    /// Returns true for WS ping and pong frames, on any channel
+    ///
+    /// These must only be sent once the other end has advertised `Capabilities::WS_PING`
+    pub fn is_ws_ping(&self) -> bool {
+        match self {
+            ProxyPacket::WsPing(_) | ProxyPacket::WsPong(_) => true,
+            ProxyPacket::Channel(_, packet) => packet.is_ws_ping(),
+            _ => false,
+        }
+    }
+
+    /// Appends the encoded packet to `dst`, which is the only time its payload gets copied
+    ///
+    /// Only an `Open` request too big for its fields can fail, which leaves part of it in `dst`
+    pub fn encode(&self, dst: &mut BytesMut) -> Result<()> {
         // Write the packet id
-        res.write(&[self.id()])?;
+        dst.put_u8(self.id());
 
         match self {
             ProxyPacket::Text(string) => {
-                res.write_all(&string.as_bytes())?;
+                dst.extend_from_slice(string.as_bytes());
+            }
+            ProxyPacket::Binary(buf)
+            | ProxyPacket::WsPing(buf)
+            | ProxyPacket::WsPong(buf)
+            | ProxyPacket::Batch(buf) => {
+                dst.extend_from_slice(buf);
+            }
+            ProxyPacket::Close(None) => {}
+            ProxyPacket::Close(Some(close)) => {
+                dst.put_u16_le(close.code);
+                dst.extend_from_slice(close.reason.as_bytes());
+            }
+            ProxyPacket::Hello(info) | ProxyPacket::HelloAck(info) => {
+                info.encode(dst);
+            }
+            ProxyPacket::Open(request) => {
+                request.encode(dst)?;
+            }
+            ProxyPacket::Fragment(fragment) => {
+                fragment.encode(dst);
+            }
+            ProxyPacket::Ping(seq) | ProxyPacket::Pong(seq) => {
+                dst.put_u64_le(*seq);
+            }
+            ProxyPacket::Sequenced(seq, packet) => {
+                dst.put_u32_le(*seq);
+                packet.encode(dst)?;
             }
-            ProxyPacket::Binary(buf) => {
-                res.write_all(&buf)?;
+            ProxyPacket::Ack(seq) => {
+                dst.put_u32_le(*seq);
+            }
+            ProxyPacket::SwitchBaud(baud_rate) | ProxyPacket::SwitchBaudAck(baud_rate) => {
+                dst.put_u32_le(*baud_rate);
+            }
+            ProxyPacket::Credit(limit) => {
+                dst.put_u32_le(*limit);
+            }
+            ProxyPacket::CreditProbe => {}
+            ProxyPacket::TimeRequest(origin) => {
+                dst.put_u64_le(*origin);
+            }
+            ProxyPacket::TimeReply(origin, remote) => {
+                dst.put_u64_le(*origin);
+                dst.put_u64_le(*remote);
+            }
+            ProxyPacket::Timestamped(timestamp, packet) => {
+                dst.put_u64_le(*timestamp);
+                packet.encode(dst)?;
+            }
+            ProxyPacket::ControlRequest(id, body) | ProxyPacket::ControlResponse(id, body) => {
+                dst.put_u32_le(*id);
+                dst.extend_from_slice(body);
+            }
+            ProxyPacket::Channel(channel, packet) => {
+                dst.put_u8(*channel);
+                packet.encode(dst)?;
             }
-            ProxyPacket::Close => {}
         };
 
-        Ok(res)
+        Ok(())
     }
 
-    pub fn decode(buf: Vec<u8>) -> Result<ProxyPacket> {
-        let mut cursor = Cursor::new(buf);
-
-        let mut id = [0u8; 1];
-        cursor.read_exact(&mut id)?;
-        let id = u8::from_le_bytes(id);
+    /// Appends the packet wrapped in a `Timestamped`, without having to box it up first
+    pub(crate) fn encode_timestamped(&self, timestamp: u64, dst: &mut BytesMut) -> Result<()> {
+        // The id of `Timestamped`
+        dst.put_u8(21);
+        dst.put_u64_le(timestamp);
+        self.encode(dst)
+    }
 
-        let mut bytes = Vec::new();
-        cursor.read_to_end(&mut bytes)?;
+    /// Decodes a packet, sharing `bytes` with any binary payload rather than copying it
+    pub fn decode(mut bytes: Bytes) -> Result<ProxyPacket> {
+        // Split off the packet id
+        if bytes.is_empty() {
+            return Err(ProtoError::EmptyFrame);
+        }
+        let id = bytes.get_u8();
 
         match id {
             // Text Packet
-            0 => {
-                let string = String::from_utf8(bytes).map_err(|_| {
-                    Error::new(ErrorKind::Other, "Could not create utf8 string from bytes")
-                })?;
-
-                Ok(ProxyPacket::Text(string))
-            }
+            0 => Ok(ProxyPacket::Text(String::from_utf8(Vec::from(bytes))?)),
             // Binary Packet
             1 => Ok(ProxyPacket::Binary(bytes)),
-            // Close Packet
-            2 => Ok(ProxyPacket::Close),
+            // Close Packet, which is empty if the WS connection closed without a code
+            2 => {
+                if bytes.is_empty() {
+                    return Ok(ProxyPacket::Close(None));
+                }
+
+                let code = decode_u16(&bytes, id)?;
+                let reason = String::from_utf8(bytes[2..].to_vec())?;
+
+                Ok(ProxyPacket::Close(Some(CloseReason { code, reason })))
+            }
+            // Hello Packet
+            3 => LinkInfo::decode(&bytes)
+                .map(ProxyPacket::Hello)
+                .ok_or(ProtoError::TruncatedPacket(id)),
+            // Hello Ack Packet
+            4 => LinkInfo::decode(&bytes)
+                .map(ProxyPacket::HelloAck)
+                .ok_or(ProtoError::TruncatedPacket(id)),
+            // Ping Packet
+            5 => Ok(ProxyPacket::Ping(decode_u64(&bytes, id)?)),
+            // Pong Packet
+            6 => Ok(ProxyPacket::Pong(decode_u64(&bytes, id)?)),
+            // Sequenced Packet
+            7 => {
+                let seq = decode_u32(&bytes, id)?;
+                bytes.advance(4);
+                let packet = ProxyPacket::decode(bytes)?;
+
+                Ok(ProxyPacket::Sequenced(seq, Box::new(packet)))
+            }
+            // Ack Packet
+            8 => Ok(ProxyPacket::Ack(decode_u32(&bytes, id)?)),
+            // Channel Packet
+            9 => {
+                if bytes.is_empty() {
+                    return Err(ProtoError::TruncatedPacket(id));
+                }
+                let channel = bytes.get_u8();
+                let packet = ProxyPacket::decode(bytes)?;
+
+                Ok(ProxyPacket::Channel(channel, Box::new(packet)))
+            }
+            // WS Ping Packet
+            11 => Ok(ProxyPacket::WsPing(bytes)),
+            // WS Pong Packet
+            12 => Ok(ProxyPacket::WsPong(bytes)),
+            // Open Packet
+            13 => Ok(ProxyPacket::Open(OpenRequest::decode(&bytes)?)),
+            // Fragment Packet
+            14 => Ok(ProxyPacket::Fragment(Fragment::decode(bytes)?)),
+            // Switch Baud Packet
+            15 => Ok(ProxyPacket::SwitchBaud(decode_u32(&bytes, id)?)),
+            // Switch Baud Ack Packet
+            16 => Ok(ProxyPacket::SwitchBaudAck(decode_u32(&bytes, id)?)),
+            // Credit Packet
+            17 => Ok(ProxyPacket::Credit(decode_u32(&bytes, id)?)),
+            // Credit Probe Packet
+            18 => Ok(ProxyPacket::CreditProbe),
+            // Time Request Packet
+            19 => Ok(ProxyPacket::TimeRequest(decode_u64(&bytes, id)?)),
+            // Time Reply Packet
+            20 => {
+                let origin = decode_u64(&bytes, id)?;
+                let remote = decode_u64(&bytes[8..], id)?;
+
+                Ok(ProxyPacket::TimeReply(origin, remote))
+            }
+            // Timestamped Packet
+            21 => {
+                let timestamp = decode_u64(&bytes, id)?;
+                bytes.advance(8);
+                let packet = ProxyPacket::decode(bytes)?;
+
+                Ok(ProxyPacket::Timestamped(timestamp, Box::new(packet)))
+            }
+            // Batch Packet
+            22 => Ok(ProxyPacket::Batch(bytes)),
+            // Control Request Packet
+            23 => {
+                let id = decode_u32(&bytes, id)?;
+                bytes.advance(4);
+
+                Ok(ProxyPacket::ControlRequest(id, bytes))
+            }
+            // Control Response Packet
+            24 => {
+                let id = decode_u32(&bytes, id)?;
+                bytes.advance(4);
+
+                Ok(ProxyPacket::ControlResponse(id, bytes))
+            }
             // Unknown packet ID
-            _ => Err(Error::new(
-                ErrorKind::Other,
-                "Invalid packet ID found when decoding packet buffer",
-            )),
+            _ => Err(ProtoError::UnknownPacketId(id)),
         }
     }
 }
 
-/// Represents anything that can have USB packets written to it
-///
-/// For this application, it will be the USB serial connection on the master DS
-pub trait ProtoWriteable: Write {
-    fn write_packet(&mut self, packet: ProxyPacket) -> Result<()>;
+/// Reads the little endian `u16` at the start of the packet with ID `id`
+fn decode_u16(bytes: &[u8], id: u8) -> Result<u16> {
+    let Some(buf) = bytes.get(..2) else {
+        return Err(ProtoError::TruncatedPacket(id));
+    };
+
+    Ok(u16::from_le_bytes([buf[0], buf[1]]))
 }
 
-impl ProtoWriteable for dyn SerialPort {
-    fn write_packet(&mut self, packet: ProxyPacket) -> Result<()> {
-        // Assert that u32 takes up 4 bytes (to make sure that encoding and decoding are consistent)
-        assert_eq!(u32::BITS / 8, 4);
+/// Reads the little endian `u32` at the start of the packet with ID `id`
+fn decode_u32(bytes: &[u8], id: u8) -> Result<u32> {
+    let Some(buf) = bytes.get(..4) else {
+        return Err(ProtoError::TruncatedPacket(id));
+    };
 
-        // Encode the payload
-        let payload = packet.encode()?;
+    Ok(u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]))
+}
 
-        // Encode the payload length in LE
-        let len = (payload.len() as u32).to_le_bytes();
+/// Reads the little endian `u64` at the start of the packet with ID `id`
+fn decode_u64(bytes: &[u8], id: u8) -> Result<u64> {
+    let Some(buf) = bytes.get(..8) else {
+        return Err(ProtoError::TruncatedPacket(id));
+    };
 
-        // Write the length first
-        self.write_all(&len)?;
+    let mut seq = [0u8; 8];
+    seq.copy_from_slice(buf);
 
-        // Write the full payload
-        self.write_all(&payload)?;
+    Ok(u64::from_le_bytes(seq))
+}
+
+/// Represents anything that can have USB packets written to it
+///
+/// For this application, it will be the USB serial connection on either end, but it works the same
+/// over anything that implements `Write` (e.g. a socket, a pty, or a `Vec<u8>`)
+///
+/// Packets are encoded by `codec` exactly as they would be on a `Framed` link, so it has to be set up
+/// to match the other end (e.g. with the same key for [`ProxyCodec::with_auth`])
+pub trait ProtoWriteable: Write {
+    fn write_packet(&mut self, packet: ProxyPacket, codec: &mut ProxyCodec) -> Result<()>;
+}
+
+impl<W: Write + ?Sized> ProtoWriteable for W {
+    fn write_packet(&mut self, packet: ProxyPacket, codec: &mut ProxyCodec) -> Result<()> {
+        // Encode the packet into a whole frame, then write it out in one go
+        let mut frame = BytesMut::new();
+        codec.encode(packet, &mut frame)?;
+        self.write_all(&frame)?;
 
         Ok(())
     }
 }
 
-/// Represents anything that can have USB packets read from it
+/// Bytes read at a time while waiting for the rest of a frame
+const READ_CHUNK_LEN: usize = 4096;
+
+/// Reads USB packets from anything that implements `Read`, for when the link isn't async
+///
+/// For this application, it will be the USB serial connection on either end, but it works the same
+/// over anything that implements `Read` (e.g. a socket, a pty, or a `Cursor`)
 ///
-/// For this application, it will be the USB serial connection on the slave rpi
-pub trait ProtoReadable: Read {
-    fn read_packet(&mut self) -> Result<ProxyPacket>;
+/// Packets are decoded by a [`ProxyCodec`], so everything a `Framed` link does happens here too:
+/// authentication tags are checked, compressed packets inflated, batches split up and fragments put
+/// back together. Frames that arrive corrupted or can't be decoded are skipped over and counted in
+/// the codec's stats, so the only errors are the reader's own.
+#[derive(Debug)]
+pub struct PacketReader<R> {
+    reader: R,
+    codec: ProxyCodec,
+    /// Bytes read that don't make up a whole frame yet
+    buf: BytesMut,
+}
+
+impl<R: Read> PacketReader<R> {
+    pub fn new(reader: R, codec: ProxyCodec) -> Self {
+        PacketReader {
+            reader,
+            codec,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// The codec packets are decoded with, e.g. to apply what the handshake settled on
+    pub fn codec_mut(&mut self) -> &mut ProxyCodec {
+        &mut self.codec
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads the next whole packet, blocking until it arrives
+    pub fn read_packet(&mut self) -> Result<ProxyPacket> {
+        let mut chunk = [0u8; READ_CHUNK_LEN];
+
+        loop {
+            if let Some(packet) = self.codec.decode(&mut self.buf)? {
+                return Ok(packet);
+            }
+
+            // Wait for more of the frame to arrive
+            let len = match self.reader.read(&mut chunk) {
+                Ok(0) => {
+                    return Err(ProtoError::Io(io::Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "USB link closed",
+                    )))
+                }
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            self.buf.extend_from_slice(&chunk[..len]);
+        }
+    }
 }
 
-impl ProtoReadable for dyn SerialPort {
-    fn read_packet(&mut self) -> Result<ProxyPacket> {
-        // Assert that u32 takes up 4 bytes (to make sure that encoding and decoding are consistent)
-        assert_eq!(u32::BITS / 8, 4);
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
 
-        // Read the first four bytes (the data length in LE)
-        let mut len = [0u8; 4];
-        self.read_exact(&mut len)?;
+    use super::*;
 
-        let len = u32::from_le_bytes(len);
+    /// One of each packet, so everything's encoding gets exercised
+    fn every_packet() -> Vec<ProxyPacket> {
+        let info = LinkInfo::new("test 1.0", Capabilities::HEARTBEAT | Capabilities::RELIABLE, 1024)
+            .with_baud_rates(vec![115_200, 921_600]);
 
-        // Read the rest of the packet (`len` bytes)
-        let mut data = vec![0u8; len as usize];
-        self.read_exact(&mut data)?;
+        vec![
+            ProxyPacket::Text("hello".into()),
+            ProxyPacket::Binary(Bytes::from_static(&[0, 1, 2, 0])),
+            ProxyPacket::Close(None),
+            ProxyPacket::Close(Some(CloseReason {
+                code: 4000,
+                reason: "duplicate name".into(),
+            })),
+            ProxyPacket::Hello(info.clone()),
+            ProxyPacket::HelloAck(info),
+            ProxyPacket::Ping(1),
+            ProxyPacket::Pong(u64::MAX),
+            ProxyPacket::Sequenced(7, Box::new(ProxyPacket::Text("seq".into()))),
+            ProxyPacket::Ack(8),
+            ProxyPacket::Binary(Bytes::from_static(b"chan")).on_channel(3),
+            ProxyPacket::WsPing(Bytes::from_static(b"ping")),
+            ProxyPacket::WsPong(Bytes::new()),
+            ProxyPacket::Open(OpenRequest::new("pi")),
+            ProxyPacket::Fragment(Fragment {
+                message: 2,
+                index: 1,
+                last: true,
+                data: Bytes::from_static(b"end"),
+            }),
+            ProxyPacket::SwitchBaud(921_600),
+            ProxyPacket::SwitchBaudAck(921_600),
+            ProxyPacket::Credit(64),
+            ProxyPacket::CreditProbe,
+            ProxyPacket::TimeRequest(10),
+            ProxyPacket::TimeReply(10, 20),
+            ProxyPacket::Timestamped(30, Box::new(ProxyPacket::Ping(2))),
+            ProxyPacket::Batch(Bytes::from_static(&[1, 0, 18])),
+            ProxyPacket::ControlRequest(1, Bytes::from_static(b"\"Ping\"")),
+            ProxyPacket::ControlResponse(1, Bytes::from_static(b"\"Pong\"")),
+        ]
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        for packet in every_packet() {
+            let mut buf = BytesMut::new();
+            packet.encode(&mut buf).unwrap();
 
-        // Read the `\r\n` at the end of the packet if it is present
-        if let Ok(num) = self.bytes_to_read() {
-            if num == 2 {
-                self.read_exact(&mut [0u8; 2])?;
+            let decoded = ProxyPacket::decode(buf.freeze()).unwrap();
+            assert_eq!(format!("{decoded:?}"), format!("{packet:?}"));
+        }
+    }
+
+    #[test]
+    fn read_and_write_packets() {
+        for framing in [Framing::LengthPrefixed, Framing::Cobs] {
+            let codec = || {
+                let mut codec = ProxyCodec::new(framing, 1024).with_auth(FrameAuth::new(b"key"));
+                codec.enable_compression(16);
+                codec
+            };
+
+            // Fragments, timestamps and batches don't make it out the other side as they went in
+            let packets: Vec<_> = every_packet()
+                .into_iter()
+                .filter(|packet| {
+                    !matches!(
+                        packet,
+                        ProxyPacket::Fragment(_) | ProxyPacket::Timestamped(..) | ProxyPacket::Batch(_)
+                    )
+                })
+                .collect();
+
+            let mut stream = vec![0x30, 0x03, 0x00, 0xff];
+            let mut writer = codec();
+            for packet in packets.clone() {
+                stream.write_packet(packet, &mut writer).unwrap();
+                // Line noise between every frame
+                stream.extend_from_slice(b"\r\n\x30");
+            }
+
+            let mut reader = PacketReader::new(Cursor::new(stream), codec());
+            for packet in packets {
+                let read = reader.read_packet().unwrap();
+                assert_eq!(format!("{read:?}"), format!("{packet:?}"), "{framing:?}");
             }
+            assert!(matches!(reader.read_packet(), Err(ProtoError::Io(_))));
+        }
+    }
+
+    #[test]
+    fn undecodable_packets_are_errors() {
+        let frames: [(&[u8], &str); 4] = [
+            (&[42], "UnknownPacketId(42)"),
+            (&[0, 0xff], "InvalidUtf8("),
+            (&[5, 1], "TruncatedPacket(5)"),
+            (&[], "EmptyFrame"),
+        ];
+
+        for (frame, expected) in frames {
+            let e = ProxyPacket::decode(Bytes::copy_from_slice(frame)).unwrap_err();
+            assert!(format!("{e:?}").starts_with(expected), "{e:?}");
+            assert!(e.is_recoverable());
         }
 
-        // Decode the packet buffer
-        let packet = ProxyPacket::decode(data).expect("could not decode packet");
+        // Each bad frame is skipped over, and reading carries on after it
+        for framing in [Framing::LengthPrefixed, Framing::Cobs] {
+            let mut stream = Vec::new();
+            for (frame, _) in frames {
+                write_frame(&mut stream, frame, framing, 1024).unwrap();
+            }
+            let mut codec = ProxyCodec::new(framing, 1024);
+            stream.write_packet(ProxyPacket::Ping(1), &mut codec).unwrap();
 
-        Ok(packet)
+            let mut reader = PacketReader::new(Cursor::new(stream), codec);
+            assert!(matches!(reader.read_packet(), Ok(ProxyPacket::Ping(1))));
+            assert_eq!(reader.codec_mut().stats().snapshot().decode_errors, 4);
+        }
     }
 }
@@ -0,0 +1,754 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use futures::channel::mpsc::{UnboundedReceiver, UnboundedSender};
+use futures::{stream, FutureExt, Sink, SinkExt, Stream, StreamExt};
+
+use crate::fragment::Reassembler;
+use crate::{
+    BaudRole, Capabilities, ClockEstimate, ClockSync, Coalesced, Coalescer, DeliveryReceipt,
+    Fragment, Fragmenter, LinkInfo, LinkParams, LinkStats, PriorityQueue, ProtoError, ProxyCodec,
+    ProxyPacket, ReceiveCredits, ReliableLink, Result, SendCredits, UnackedGate,
+    CLOCK_SYNC_INTERVAL, CREDIT_PROBE_INTERVAL, MAX_BATCH_LEN,
+};
+
+/// Most packets the write loop holds on to while it works out which to send first
+///
+/// Anything more is left in the channels it came from, so whoever is sending it waits on the link
+const MAX_QUEUED_PACKETS: usize = 32;
+
+/// Settings for running a connection, which only affect this end of the link
+#[derive(Debug, Clone)]
+pub struct LinkConfig {
+    /// How often to send a `Ping`, if both ends asked for heartbeats
+    pub heartbeat_interval: Duration,
+    /// How long the other end can stay silent before the link is declared dead
+    pub link_timeout: Duration,
+    /// How long to wait for packets to be acknowledged before resending them
+    pub retransmit_timeout: Duration,
+    /// How many data packets from the other end can be waiting to be passed on
+    pub credit_window: u32,
+    /// Smallest text or binary packet worth compressing, in bytes
+    pub compression_threshold: usize,
+    /// Largest piece of a big binary packet to send at once, in bytes
+    pub fragment_size: usize,
+    /// Longest a small packet is held back waiting for others to share its frame
+    pub coalesce_window: Duration,
+    /// Which end ran the baud rate switch, so the leader can confirm it again if the follower missed it
+    pub baud_role: BaudRole,
+}
+
+/// Why [`Connection::next_packet`] gave up on the link
+#[derive(Debug)]
+pub enum LinkDown {
+    /// Nothing was received from the other end for the link timeout
+    Silent(Duration),
+    /// Reading from the port failed, which means the port itself is gone since bad frames are dropped
+    /// by the codec
+    Failed(ProtoError),
+    /// The port was closed
+    Closed,
+}
+
+/// What a packet from the other end turned out to be, once the link's own packets are dealt with
+#[derive(Debug)]
+pub enum Received {
+    /// Nothing for anything outside the link
+    Nothing,
+    /// Data to pass on, in the order it was sent
+    Data(ProxyPacket),
+    /// A command for this end, to be answered with a `ControlResponse` with the same ID
+    ControlRequest(u32, Bytes),
+    /// The answer to a command this end sent
+    ControlResponse(u32, Bytes),
+    /// A new estimate of how far the other end's clock is from ours, already recorded in the stats
+    Clock(ClockEstimate),
+    /// The other end restarted its end of the link, so the link has to be reopened and handshaken again
+    Restarted,
+}
+
+/// Everything both ends keep track of while a handshaken link is up
+///
+/// Heartbeats, clock syncs, credit probes and resends run in the background from [`Connection::start`]
+/// until the receiver it returns is dropped, which [`Connection::write`] does once the link goes down.
+#[derive(Debug)]
+pub struct Connection {
+    local: LinkInfo,
+    params: LinkParams,
+    config: LinkConfig,
+    stats: Arc<LinkStats>,
+    /// Link level packets generated while reading, which have to be written back to the other end
+    link_tx: UnboundedSender<ProxyPacket>,
+    reliable: Option<Arc<Mutex<ReliableLink>>>,
+    /// Messages the other end is part way through sending in sequenced fragments
+    fragments: Mutex<Reassembler>,
+    send_credits: Option<Arc<SendCredits>>,
+    receive_credits: Option<Arc<Mutex<ReceiveCredits>>>,
+    /// Counts data packets as used up, once each one's [`DeliveryReceipt`] is dropped
+    delivered_tx: Option<UnboundedSender<()>>,
+    clock_sync: Option<Arc<Mutex<ClockSync>>>,
+}
+
+impl Connection {
+    /// Sets up everything `params` turned on, returning the queue of link level packets to hand to
+    /// [`Connection::write`]
+    pub fn start(
+        local: LinkInfo,
+        params: LinkParams,
+        config: LinkConfig,
+        stats: Arc<LinkStats>,
+    ) -> (Self, UnboundedReceiver<ProxyPacket>) {
+        let (link_tx, link_rx) = futures::channel::mpsc::unbounded();
+        let capabilities = params.capabilities;
+
+        // Let the other end know we're still here, for as long as this connection lasts
+        if capabilities.contains(Capabilities::HEARTBEAT) {
+            tokio::spawn(send_heartbeats(link_tx.clone(), config.heartbeat_interval));
+        }
+
+        // Work out how far the other end's clock is from ours, so timestamped frames can be measured
+        stats.clear_clock();
+        let clock_sync = capabilities
+            .contains(Capabilities::TIMESTAMPS)
+            .then(|| Arc::new(Mutex::new(ClockSync::new())));
+        if let Some(clock_sync) = &clock_sync {
+            tokio::spawn(sync_clocks(
+                clock_sync.clone(),
+                link_tx.clone(),
+                CLOCK_SYNC_INTERVAL,
+            ));
+        }
+
+        // Keep track of sequence numbers and resend anything lost, if both ends asked for it
+        let reliable = capabilities
+            .contains(Capabilities::RELIABLE)
+            .then(|| Arc::new(Mutex::new(ReliableLink::new(config.retransmit_timeout))));
+        if let Some(reliable) = &reliable {
+            tokio::spawn(resend_unacked(
+                reliable.clone(),
+                link_tx.clone(),
+                config.retransmit_timeout / 4,
+            ));
+        }
+
+        // Only send the other end data it has room for and vice versa, if both ends asked for it and
+        // nothing can get lost on the way
+        let flow_control = capabilities.contains(Capabilities::FLOW_CONTROL) && reliable.is_some();
+        let send_credits = flow_control.then(|| Arc::new(SendCredits::new()));
+        let receive_credits =
+            flow_control.then(|| Arc::new(Mutex::new(ReceiveCredits::new(config.credit_window))));
+        let mut delivered_tx = None;
+        if let Some(receive_credits) = &receive_credits {
+            let _ = link_tx.unbounded_send(receive_credits.lock().unwrap().grant());
+
+            let (tx, delivered_rx) = futures::channel::mpsc::unbounded();
+            tokio::spawn(grant_credits(
+                receive_credits.clone(),
+                delivered_rx,
+                link_tx.clone(),
+            ));
+            delivered_tx = Some(tx);
+        }
+        if let Some(send_credits) = &send_credits {
+            tokio::spawn(probe_credits(
+                send_credits.clone(),
+                link_tx.clone(),
+                CREDIT_PROBE_INTERVAL,
+            ));
+        }
+
+        let connection = Connection {
+            local,
+            params,
+            config,
+            stats,
+            link_tx,
+            reliable,
+            fragments: Mutex::default(),
+            send_credits,
+            receive_credits,
+            delivered_tx,
+            clock_sync,
+        };
+
+        (connection, link_rx)
+    }
+
+    /// What both ends agreed on in the handshake
+    pub fn params(&self) -> &LinkParams {
+        &self.params
+    }
+
+    /// Sets `codec` up for everything both ends agreed on, once the handshake is over
+    pub fn configure(&self, codec: &mut ProxyCodec) {
+        let capabilities = self.params.capabilities;
+
+        // Neither end may send frames bigger than the other accepts
+        codec.set_max_frame_size(self.params.max_frame_size);
+
+        // Shrink big packets before they cross the link, if both ends know how to inflate them again
+        if capabilities.contains(Capabilities::COMPRESSION) {
+            codec.enable_compression(self.config.compression_threshold);
+        }
+
+        // Stamp frames with when they went out, if the other end can make sense of them
+        if capabilities.contains(Capabilities::TIMESTAMPS) {
+            codec.enable_timestamps();
+        }
+    }
+
+    /// Queues up a packet to go out alongside the WS data, e.g. an `Open` or a `ControlRequest`
+    ///
+    /// Anything sent once the connection is being torn down is dropped.
+    pub fn send(&self, packet: ProxyPacket) {
+        let _ = self.link_tx.unbounded_send(packet);
+    }
+
+    /// Reads the next packet from `reader`, giving up on the other end if it goes quiet for longer than
+    /// the link timeout while heartbeats are on
+    pub async fn next_packet<S>(&self, reader: &mut S) -> std::result::Result<ProxyPacket, LinkDown>
+    where
+        S: Stream<Item = Result<ProxyPacket>> + Unpin,
+    {
+        let packet = if self.params.capabilities.contains(Capabilities::HEARTBEAT) {
+            tokio::time::timeout(self.config.link_timeout, reader.next())
+                .await
+                .map_err(|_| LinkDown::Silent(self.config.link_timeout))?
+        } else {
+            reader.next().await
+        };
+
+        match packet {
+            Some(Ok(packet)) => Ok(packet),
+            Some(Err(e)) => Err(LinkDown::Failed(e)),
+            None => Err(LinkDown::Closed),
+        }
+    }
+
+    /// Deals with a packet from the other end, answering anything that only runs the link
+    pub fn receive(&self, packet: ProxyPacket) -> Received {
+        match packet {
+            // Sent again during the handshake, before our answer made it across
+            ProxyPacket::Hello(peer)
+                if peer.session != 0 && peer.session == self.params.peer.session =>
+            {
+                self.send(ProxyPacket::HelloAck(self.local.clone()));
+            }
+            // Anything agreed on in the handshake could be different now, so it has to be redone
+            ProxyPacket::Hello(_) => return Received::Restarted,
+            ProxyPacket::HelloAck(_) => {}
+            // The follower is still waiting to hear that the switch went through
+            ProxyPacket::SwitchBaudAck(baud_rate)
+                if self.config.baud_role == BaudRole::Leader
+                    && self.params.baud_rate == Some(baud_rate) =>
+            {
+                self.send(ProxyPacket::SwitchBaudAck(baud_rate));
+            }
+            // Left over from switching baud rates
+            ProxyPacket::SwitchBaud(_) | ProxyPacket::SwitchBaudAck(_) => {}
+            ProxyPacket::Credit(limit) => {
+                if let Some(send_credits) = &self.send_credits {
+                    send_credits.on_credit(limit);
+                }
+            }
+            ProxyPacket::CreditProbe => {
+                if let Some(receive_credits) = &self.receive_credits {
+                    self.send(receive_credits.lock().unwrap().grant());
+                }
+            }
+            ProxyPacket::Ping(seq) => self.send(ProxyPacket::Pong(seq)),
+            ProxyPacket::Pong(_) => {}
+            ProxyPacket::TimeRequest(origin) => {
+                if let Some(clock_sync) = &self.clock_sync {
+                    self.send(clock_sync.lock().unwrap().reply(origin));
+                }
+            }
+            ProxyPacket::TimeReply(origin, remote) => {
+                if let Some(clock_sync) = &self.clock_sync {
+                    let estimate = clock_sync.lock().unwrap().on_reply(origin, remote);
+                    self.stats.record_clock(estimate);
+                    return Received::Clock(estimate);
+                }
+            }
+            ProxyPacket::ControlRequest(id, body) => return Received::ControlRequest(id, body),
+            ProxyPacket::ControlResponse(id, body) => return Received::ControlResponse(id, body),
+            ProxyPacket::Sequenced(seq, packet) => {
+                if let Some(reliable) = &self.reliable {
+                    let (delivered, ack) = reliable.lock().unwrap().receive(seq, *packet);
+                    self.send(ack);
+
+                    // Pass the packet on, unless it was a duplicate or arrived out of order
+                    match delivered {
+                        Some(ProxyPacket::Fragment(fragment)) => return self.reassemble(fragment),
+                        Some(packet) => return Received::Data(packet),
+                        None => {}
+                    }
+                }
+            }
+            ProxyPacket::Ack(seq) => {
+                if let Some(reliable) = &self.reliable {
+                    reliable.lock().unwrap().on_ack(seq);
+                }
+            }
+            packet => return Received::Data(packet),
+        }
+
+        Received::Nothing
+    }
+
+    /// Adds a fragment that was sequenced to its message, passing the message on once it's whole
+    ///
+    /// Fragments that weren't sequenced are put back together by the codec instead.
+    fn reassemble(&self, fragment: Fragment) -> Received {
+        let data = self
+            .fragments
+            .lock()
+            .unwrap()
+            .push(fragment, self.params.max_frame_size);
+
+        let packet = match data {
+            Ok(Some(data)) => ProxyPacket::decode(data),
+            Ok(None) => return Received::Nothing,
+            Err(e) => Err(e),
+        };
+
+        match packet {
+            Ok(packet) => Received::Data(packet),
+            Err(e) => {
+                self.stats.record_error(&e);
+                Received::Nothing
+            }
+        }
+    }
+
+    /// Takes up room for a data packet the other end sent, until the receipt is dropped
+    ///
+    /// Send the receipt along with the packet, and only drop it once the packet has been used up
+    pub fn receipt(&self) -> DeliveryReceipt {
+        DeliveryReceipt::new(self.delivered_tx.clone())
+    }
+
+    /// Writes the link's own packets from `link_rx` and data from `data` to `writer`, until writing fails
+    ///
+    /// Packets that can't be sent without taking the link down with them are handed to `on_dropped`.
+    /// Returns the error that took the link down.
+    pub async fn write<W, D>(
+        &self,
+        writer: &mut W,
+        link_rx: UnboundedReceiver<ProxyPacket>,
+        data: &mut D,
+        mut on_dropped: impl FnMut(ProtoError),
+    ) -> ProtoError
+    where
+        W: Sink<ProxyPacket, Error = ProtoError> + Unpin,
+        D: Stream<Item = ProxyPacket> + Unpin,
+    {
+        let capabilities = self.params.capabilities;
+
+        // Data packets only come through while the other end has room for them
+        let data = match &self.send_credits {
+            Some(send_credits) => send_credits.gate(data).left_stream(),
+            None => data.right_stream(),
+        };
+
+        // Nor while too many are still waiting on an `Ack`
+        let data = match &self.reliable {
+            Some(reliable) => UnackedGate::new(reliable.clone(), data).left_stream(),
+            None => data.right_stream(),
+        };
+
+        // Link level packets go out alongside the data
+        let mut outgoing = stream::select(link_rx, data);
+
+        // Packets waiting their turn, so the link's own packets and NT4 control messages go ahead of
+        // bulk data
+        let mut queue = PriorityQueue::new();
+
+        // Send big binary packets a piece at a time, if the other end can put them back together
+        let mut fragmenter = capabilities
+            .contains(Capabilities::FRAGMENTATION)
+            .then(|| Fragmenter::new(self.config.fragment_size, self.params.max_frame_size));
+
+        // Small packets waiting for others to share a frame with, if the other end can split them up again
+        let mut coalescer = capabilities.contains(Capabilities::BATCHING).then(|| {
+            Coalescer::new(
+                self.config.coalesce_window,
+                MAX_BATCH_LEN.min(self.params.max_frame_size as usize / 2),
+            )
+        });
+
+        loop {
+            // Wait for the next packet, unless there are fragments still to send
+            let fragments_left = fragmenter.as_ref().is_some_and(|f| !f.is_empty());
+            if queue.is_empty() && !fragments_left {
+                // Only for as long as the packets held back can still wait though
+                let packet = match coalescer.as_ref().and_then(Coalescer::deadline) {
+                    Some(deadline) => tokio::time::timeout_at(deadline, outgoing.next())
+                        .await
+                        .unwrap_or(None),
+                    None => outgoing.next().await,
+                };
+
+                if let Some(packet) = packet {
+                    queue.push(packet);
+                }
+            }
+
+            // Queue up everything else that's ready, so the most urgent packet goes first
+            let held = fragmenter.as_ref().map_or(0, Fragmenter::len);
+            while queue.len() + held < MAX_QUEUED_PACKETS {
+                match outgoing.next().now_or_never() {
+                    Some(Some(packet)) => queue.push(packet),
+                    _ => break,
+                }
+            }
+
+            // Fragments, and whatever is waiting on them, only go out when there's nothing else to send
+            let packet = match queue.pop() {
+                Some(packet) => {
+                    // WS pings and pongs go no further if the other end doesn't know what they are
+                    if packet.is_ws_ping() && !capabilities.contains(Capabilities::WS_PING) {
+                        // It never reaches the other end, so it can't use up any of its room
+                        if let Some(send_credits) = &self.send_credits {
+                            send_credits.refund();
+                        }
+                        continue;
+                    }
+
+                    // Big binary packets go out a piece at a time in between other channels' packets,
+                    // with anything after one on its own channel waiting for it
+                    match &mut fragmenter {
+                        Some(fragmenter) => match fragmenter.push(packet) {
+                            Ok(Some(packet)) => Some(packet),
+                            Ok(None) => continue,
+                            Err(e) => {
+                                on_dropped(e);
+                                continue;
+                            }
+                        },
+                        None => Some(packet),
+                    }
+                }
+                None => fragmenter.as_mut().and_then(Fragmenter::next_packet),
+            };
+
+            let ready = match packet {
+                // Nothing else turned up in time to share a frame with the packets held back
+                None => match coalescer.as_mut().and_then(Coalescer::flush) {
+                    Some(packet) => Coalesced::from(packet),
+                    // If no packet is available, keep looping until one is
+                    None => continue,
+                },
+                Some(packet) => {
+                    // Give data packets and each piece of a big one the next sequence number, so nothing
+                    // has to wait on a whole message being resent. Resends and link packets go out as they are
+                    let packet = match &self.reliable {
+                        Some(reliable)
+                            if !packet.is_link_control()
+                                || matches!(packet, ProxyPacket::Fragment(_)) =>
+                        {
+                            reliable.lock().unwrap().wrap(packet)
+                        }
+                        _ => packet,
+                    };
+
+                    // Small packets wait a moment for others to share a frame with
+                    match &mut coalescer {
+                        Some(coalescer) => coalescer.push(packet),
+                        None => Coalesced::from(packet),
+                    }
+                }
+            };
+
+            // Write the packets to the stream
+            for packet in ready {
+                match writer.send(packet).await {
+                    Ok(_) => {}
+                    // Only this packet is lost, the link itself is still fine
+                    Err(e) if e.is_recoverable() => on_dropped(e),
+                    Err(e) => return e,
+                }
+            }
+        }
+    }
+}
+
+/// Queues up a `Ping` every `interval`, until the connection it was sending on is torn down
+async fn send_heartbeats(link_tx: UnboundedSender<ProxyPacket>, interval: Duration) {
+    for seq in 0u64.. {
+        tokio::time::sleep(interval).await;
+
+        if link_tx.unbounded_send(ProxyPacket::Ping(seq)).is_err() {
+            break;
+        }
+    }
+}
+
+/// Queues up a `TimeRequest` every `interval`, until the connection it was sending on is torn down
+async fn sync_clocks(
+    clock_sync: Arc<Mutex<ClockSync>>,
+    link_tx: UnboundedSender<ProxyPacket>,
+    interval: Duration,
+) {
+    loop {
+        let request = clock_sync.lock().unwrap().request();
+        if link_tx.unbounded_send(request).is_err() {
+            break;
+        }
+
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Queues up more credit for the other end as the data packets it sent are used up, until the connection
+/// it was sending on is torn down and every receipt from it has been dropped
+async fn grant_credits(
+    receive_credits: Arc<Mutex<ReceiveCredits>>,
+    mut delivered_rx: UnboundedReceiver<()>,
+    link_tx: UnboundedSender<ProxyPacket>,
+) {
+    while delivered_rx.next().await.is_some() {
+        let credit = receive_credits.lock().unwrap().on_delivered();
+        if let Some(credit) = credit {
+            if link_tx.unbounded_send(credit).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Queues up a `CreditProbe` every `interval` the other end leaves us without credit, in case its `Credit`
+/// got lost, until the connection it was sending on is torn down
+async fn probe_credits(
+    send_credits: Arc<SendCredits>,
+    link_tx: UnboundedSender<ProxyPacket>,
+    interval: Duration,
+) {
+    while !link_tx.is_closed() {
+        tokio::time::sleep(interval).await;
+
+        if send_credits.is_exhausted() && link_tx.unbounded_send(ProxyPacket::CreditProbe).is_err()
+        {
+            return;
+        }
+    }
+}
+
+/// Queues up anything the other end hasn't acknowledged in time, until the connection it was sending on is torn down
+async fn resend_unacked(
+    reliable: Arc<Mutex<ReliableLink>>,
+    link_tx: UnboundedSender<ProxyPacket>,
+    interval: Duration,
+) {
+    while !link_tx.is_closed() {
+        tokio::time::sleep(interval).await;
+
+        let due = reliable.lock().unwrap().due_retransmits(Instant::now());
+        for packet in due {
+            if link_tx.unbounded_send(packet).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{select, Either};
+    use futures::pin_mut;
+
+    use super::*;
+    use crate::handshake::tests::link_pair;
+
+    fn config() -> LinkConfig {
+        LinkConfig {
+            heartbeat_interval: Duration::from_millis(250),
+            link_timeout: Duration::from_millis(1500),
+            retransmit_timeout: Duration::from_millis(500),
+            credit_window: 16,
+            compression_threshold: 128,
+            fragment_size: 256,
+            coalesce_window: Duration::from_millis(1),
+            baud_role: BaudRole::Leader,
+        }
+    }
+
+    fn start(capabilities: Capabilities) -> (Connection, UnboundedReceiver<ProxyPacket>) {
+        let local = LinkInfo::new("test 1.0", capabilities, 1024).with_baud_rates(vec![115_200]);
+        let params = local.negotiate(&local).unwrap();
+        Connection::start(local, params, config(), Arc::new(LinkStats::default()))
+    }
+
+    #[tokio::test]
+    async fn link_packets_are_answered() {
+        let (connection, mut link_rx) = start(Capabilities::RELIABLE);
+
+        assert!(matches!(
+            connection.receive(ProxyPacket::Ping(3)),
+            Received::Nothing
+        ));
+        assert!(matches!(link_rx.next().await, Some(ProxyPacket::Pong(3))));
+
+        let received = connection.receive(ProxyPacket::Sequenced(
+            0,
+            Box::new(ProxyPacket::Text("a".into())),
+        ));
+        assert!(matches!(received, Received::Data(ProxyPacket::Text(_))));
+        assert!(matches!(link_rx.next().await, Some(ProxyPacket::Ack(1))));
+
+        // A duplicate is acknowledged again, but not passed on twice
+        let received = connection.receive(ProxyPacket::Sequenced(
+            0,
+            Box::new(ProxyPacket::Text("a".into())),
+        ));
+        assert!(matches!(received, Received::Nothing));
+    }
+
+    #[tokio::test]
+    async fn only_a_restarted_end_starts_over() {
+        let (connection, mut link_rx) = start(Capabilities::RELIABLE | Capabilities::BAUD_SWITCH);
+        let peer = connection.params().peer.clone();
+
+        // The other end sent its `Hello` again before our answer got there
+        assert!(matches!(
+            connection.receive(ProxyPacket::Hello(peer.clone())),
+            Received::Nothing
+        ));
+        assert!(matches!(
+            link_rx.next().await,
+            Some(ProxyPacket::HelloAck(_))
+        ));
+
+        let restarted = LinkInfo {
+            session: peer.session.wrapping_add(1),
+            ..peer
+        };
+        assert!(matches!(
+            connection.receive(ProxyPacket::Hello(restarted)),
+            Received::Restarted
+        ));
+    }
+
+    #[tokio::test]
+    async fn leader_confirms_a_baud_switch_again() {
+        let (connection, mut link_rx) = start(Capabilities::BAUD_SWITCH);
+        let baud_rate = connection.params().baud_rate.unwrap();
+
+        connection.receive(ProxyPacket::SwitchBaudAck(baud_rate));
+        assert!(matches!(
+            link_rx.next().await,
+            Some(ProxyPacket::SwitchBaudAck(confirmed)) if confirmed == baud_rate
+        ));
+    }
+
+    #[tokio::test]
+    async fn data_crosses_in_order() {
+        // Batches are split up by the codec, which a test link doesn't have
+        let (sender, link_rx) = start(Capabilities::RELIABLE);
+        let (receiver, _receiver_link_rx) = start(Capabilities::RELIABLE);
+        let (mut a, mut b) = link_pair();
+
+        let (data_tx, mut data_rx) = futures::channel::mpsc::unbounded();
+        for n in 0..20 {
+            data_tx
+                .unbounded_send(ProxyPacket::Text(n.to_string()))
+                .unwrap();
+        }
+
+        let write = sender.write(&mut a, link_rx, &mut data_rx, |e| panic!("{}", e));
+        let read = async {
+            let mut texts = Vec::new();
+            while texts.len() < 20 {
+                let packet = receiver.next_packet(&mut b).await.unwrap();
+                if let Received::Data(ProxyPacket::Text(text)) = receiver.receive(packet) {
+                    texts.push(text);
+                }
+            }
+            texts
+        };
+
+        pin_mut!(write, read);
+        match select(write, read).await {
+            Either::Left((e, _)) => panic!("{}", e),
+            Either::Right((texts, _)) => {
+                let expected: Vec<_> = (0..20).map(|n| n.to_string()).collect();
+                assert_eq!(texts, expected);
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_links_leave_data_where_it_is() {
+        let (sender, link_rx) = start(Capabilities::empty());
+
+        // Takes the first packet, then never has room for another
+        let mut writer = Box::pin(futures::sink::unfold((), |_, _: ProxyPacket| async {
+            std::future::pending::<()>().await;
+            Ok::<_, ProtoError>(())
+        }));
+
+        let taken = std::cell::Cell::new(0);
+        let mut data = stream::iter(0..1000).map(|n| {
+            taken.set(taken.get() + 1);
+            ProxyPacket::Text(n.to_string())
+        });
+
+        let write = sender.write(&mut writer, link_rx, &mut data, |e| panic!("{}", e));
+        assert!(tokio::time::timeout(Duration::from_secs(1), write)
+            .await
+            .is_err());
+        assert!(taken.get() <= MAX_QUEUED_PACKETS + 1, "{}", taken.get());
+    }
+
+    #[tokio::test]
+    async fn credit_waits_for_data_to_be_used_up() {
+        let (connection, mut link_rx) = start(Capabilities::RELIABLE | Capabilities::FLOW_CONTROL);
+        assert!(matches!(
+            link_rx.next().await,
+            Some(ProxyPacket::Credit(16))
+        ));
+
+        // Passing packets on isn't enough, whatever they were passed on to has to be done with them
+        let receipts: Vec<_> = (0..8).map(|_| connection.receipt()).collect();
+        tokio::task::yield_now().await;
+        assert!(link_rx.next().now_or_never().is_none());
+
+        drop(receipts);
+        assert!(matches!(
+            link_rx.next().await,
+            Some(ProxyPacket::Credit(24))
+        ));
+    }
+
+    #[tokio::test]
+    async fn packets_sent_between_fragments_are_not_held_up() {
+        let (receiver, _link_rx) = start(Capabilities::RELIABLE | Capabilities::FRAGMENTATION);
+
+        // What the write loop does with a big packet and a small one for another channel that turns up
+        // part way through it
+        let mut fragmenter = Fragmenter::new(256, 1024);
+        let mut reliable = ReliableLink::new(Duration::from_millis(500));
+        let big = ProxyPacket::Binary(Bytes::from(vec![1; 900]));
+        assert!(fragmenter.push(big.clone()).unwrap().is_none());
+
+        let small = ProxyPacket::Text("small".into()).on_channel(1);
+        assert!(fragmenter.push(small.clone()).unwrap().is_some());
+
+        let mut sent = vec![reliable.wrap(fragmenter.next_packet().unwrap())];
+        sent.push(reliable.wrap(small.clone()));
+        while let Some(fragment) = fragmenter.next_packet() {
+            sent.push(reliable.wrap(fragment));
+        }
+
+        // Everything arrives in order the first time around, so nothing has to be resent
+        let delivered: Vec<_> = sent
+            .into_iter()
+            .filter_map(|packet| match receiver.receive(packet) {
+                Received::Data(packet) => Some(format!("{:?}", packet)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(delivered, [format!("{:?}", small), format!("{:?}", big)]);
+    }
+}
@@ -0,0 +1,175 @@
+use std::io::{Cursor, Read};
+
+use bytes::{BufMut, BytesMut};
+use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+
+use crate::{ProtoError, Result};
+
+/// Packet ID of `Open` packets, for reporting truncated ones
+const OPEN_PACKET_ID: u8 = 13;
+
+/// WS subprotocol spoken by NT4 clients
+pub const NT4_SUBPROTOCOL: &str = "networktables.first.wpi.edu";
+
+/// Characters escaped in a client name, so it stays one segment of the path whatever it contains
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'/')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'\\')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// What the console wants the WS connection to the NT server for its channel to look like
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenRequest {
+    /// NT4 client name, which goes in the WS path unless `path` is set
+    pub client_name: String,
+    /// WS subprotocols to offer the NT server, most preferred first
+    pub subprotocols: Vec<String>,
+    /// Path and query to connect to instead of `/nt/<client_name>`
+    pub path: Option<String>,
+}
+
+impl OpenRequest {
+    /// Asks for a plain NT4 connection under `client_name`
+    pub fn new(client_name: impl Into<String>) -> Self {
+        OpenRequest {
+            client_name: client_name.into(),
+            subprotocols: vec![NT4_SUBPROTOCOL.to_string()],
+            path: None,
+        }
+    }
+
+    /// Path and query to connect to on the NT server
+    pub fn path_and_query(&self) -> String {
+        match &self.path {
+            Some(path) => path.clone(),
+            None => format!("/nt/{}", utf8_percent_encode(&self.client_name, PATH_SEGMENT)),
+        }
+    }
+
+    pub(crate) fn encode(&self, res: &mut BytesMut) -> Result<()> {
+        encode_str(res, &self.client_name, "client name length")?;
+
+        let count = u8::try_from(self.subprotocols.len()).map_err(|_| {
+            ProtoError::OpenRequestTooLarge {
+                field: "subprotocol count",
+                len: self.subprotocols.len(),
+                max: u8::MAX as usize,
+            }
+        })?;
+        res.put_u8(count);
+        for subprotocol in &self.subprotocols {
+            encode_str(res, subprotocol, "subprotocol length")?;
+        }
+
+        // An empty path would never be valid, so it stands in for no path at all
+        encode_str(res, self.path.as_deref().unwrap_or_default(), "path length")
+    }
+
+    pub(crate) fn decode(bytes: &[u8]) -> Result<OpenRequest> {
+        let mut cursor = Cursor::new(bytes);
+
+        let client_name = decode_str(&mut cursor)?;
+
+        let mut count = [0u8; 1];
+        cursor
+            .read_exact(&mut count)
+            .map_err(|_| ProtoError::TruncatedPacket(OPEN_PACKET_ID))?;
+
+        let subprotocols = (0..count[0])
+            .map(|_| decode_str(&mut cursor))
+            .collect::<Result<Vec<_>>>()?;
+
+        let path = decode_str(&mut cursor)?;
+
+        Ok(OpenRequest {
+            client_name,
+            subprotocols,
+            path: (!path.is_empty()).then_some(path),
+        })
+    }
+}
+
+/// Writes a string with its length in front, refusing one too long for its length to fit
+fn encode_str(res: &mut BytesMut, string: &str, field: &'static str) -> Result<()> {
+    let len = u16::try_from(string.len()).map_err(|_| ProtoError::OpenRequestTooLarge {
+        field,
+        len: string.len(),
+        max: u16::MAX as usize,
+    })?;
+
+    res.put_u16_le(len);
+    res.extend_from_slice(string.as_bytes());
+
+    Ok(())
+}
+
+/// Reads a string written by `encode_str`
+fn decode_str(cursor: &mut Cursor<&[u8]>) -> Result<String> {
+    let mut len = [0u8; 2];
+    cursor
+        .read_exact(&mut len)
+        .map_err(|_| ProtoError::TruncatedPacket(OPEN_PACKET_ID))?;
+
+    let mut string = vec![0u8; u16::from_le_bytes(len) as usize];
+    cursor
+        .read_exact(&mut string)
+        .map_err(|_| ProtoError::TruncatedPacket(OPEN_PACKET_ID))?;
+
+    Ok(String::from_utf8(string)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_names_stay_in_the_path() {
+        assert_eq!(OpenRequest::new("pi").path_and_query(), "/nt/pi");
+        assert_eq!(
+            OpenRequest::new("../x?y=1#z").path_and_query(),
+            "/nt/..%2Fx%3Fy=1%23z"
+        );
+        assert_eq!(OpenRequest::new("a b\\c").path_and_query(), "/nt/a%20b%5Cc");
+    }
+
+    #[test]
+    fn oversized_requests_are_refused() {
+        let mut request = OpenRequest::new("pi");
+        request.subprotocols = vec![String::new(); 256];
+        assert!(matches!(
+            request.encode(&mut BytesMut::new()),
+            Err(ProtoError::OpenRequestTooLarge { len: 256, max: 255, .. })
+        ));
+
+        let mut request = OpenRequest::new("pi");
+        request.path = Some("/".repeat(u16::MAX as usize + 1));
+        assert!(matches!(
+            request.encode(&mut BytesMut::new()),
+            Err(ProtoError::OpenRequestTooLarge { max: 65535, .. })
+        ));
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let mut request = OpenRequest::new("pi");
+        request.subprotocols.push("v4.1".into());
+        request.path = Some("/nt/pi?x=1".into());
+
+        let mut buf = BytesMut::new();
+        request.encode(&mut buf).unwrap();
+        assert_eq!(OpenRequest::decode(&buf).unwrap(), request);
+        assert!(matches!(
+            OpenRequest::decode(&buf[..buf.len() - 1]),
+            Err(ProtoError::TruncatedPacket(OPEN_PACKET_ID))
+        ));
+    }
+}
@@ -0,0 +1,167 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::ProxyPacket;
+
+/// Largest `Binary` packet still sent ahead of bulk data, e.g. a value update from a driver input
+pub const MAX_CONTROL_BINARY_LEN: usize = 128;
+
+/// How urgently a packet needs to cross the link, most urgent first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Packets that keep the link itself going, like heartbeats, acks and credit, which the other end is waiting on
+    Link,
+    /// NT4 control messages and small value updates, which something is waiting on
+    Control,
+    /// Everything else, mostly large value updates for dashboards
+    Bulk,
+}
+
+impl ProxyPacket {
+    /// How urgently this packet needs to cross the link
+    ///
+    /// `Sequenced` packets are only ever queued up when they're being resent, and go first so the
+    /// other end can stop holding back everything sent after them
+    pub fn priority(&self) -> Priority {
+        match self {
+            ProxyPacket::Binary(data) if data.len() > MAX_CONTROL_BINARY_LEN => Priority::Bulk,
+            ProxyPacket::Fragment(_) => Priority::Bulk,
+            ProxyPacket::Channel(_, packet) => packet.priority(),
+            packet if packet.is_link_control() => Priority::Link,
+            _ => Priority::Control,
+        }
+    }
+}
+
+/// Packets waiting to be written to the link, handed out most urgent first
+///
+/// WS data is queued up per channel and each channel keeps its own order, since NT4 messages depend
+/// on the ones before them (e.g. a value update on a topic announced just before it). Only the packet
+/// at the front of each channel competes with the other channels, so a `Text` message can go ahead of
+/// bulk data queued up on another channel but never ahead of its own.
+#[derive(Debug, Default)]
+pub struct PriorityQueue {
+    /// The link's own packets, which always go first
+    link: VecDeque<ProxyPacket>,
+    /// WS data for each channel, numbered in the order it was queued up to break ties between channels
+    channels: BTreeMap<u8, VecDeque<(u64, ProxyPacket)>>,
+    next: u64,
+    len: usize,
+}
+
+impl PriorityQueue {
+    pub fn new() -> Self {
+        PriorityQueue::default()
+    }
+
+    pub fn push(&mut self, packet: ProxyPacket) {
+        self.len += 1;
+
+        if packet.priority() == Priority::Link {
+            self.link.push_back(packet);
+            return;
+        }
+
+        let channel = match &packet {
+            ProxyPacket::Channel(channel, _) => *channel,
+            _ => 0,
+        };
+
+        self.channels
+            .entry(channel)
+            .or_default()
+            .push_back((self.next, packet));
+        self.next += 1;
+    }
+
+    /// Takes the most urgent packet at the front of any channel, or the oldest one if there's a tie
+    pub fn pop(&mut self) -> Option<ProxyPacket> {
+        if let Some(packet) = self.link.pop_front() {
+            self.len -= 1;
+            return Some(packet);
+        }
+
+        let channel = self
+            .channels
+            .iter()
+            .filter_map(|(channel, queue)| {
+                let (order, packet) = queue.front()?;
+                Some((packet.priority(), *order, *channel))
+            })
+            .min()?
+            .2;
+
+        let queue = self.channels.get_mut(&channel)?;
+        let (_, packet) = queue.pop_front()?;
+        if queue.is_empty() {
+            self.channels.remove(&channel);
+        }
+
+        self.len -= 1;
+        Some(packet)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use super::*;
+
+    #[test]
+    fn data_keeps_its_order() {
+        let mut queue = PriorityQueue::new();
+        queue.push(ProxyPacket::Binary(Bytes::from(vec![0; 1000])));
+        queue.push(ProxyPacket::Text("announce".into()));
+        queue.push(ProxyPacket::Ping(1));
+        queue.push(ProxyPacket::Binary(Bytes::from_static(b"small")).on_channel(1));
+        queue.push(ProxyPacket::Close(None));
+        queue.push(ProxyPacket::Ack(2));
+        assert_eq!(queue.len(), 6);
+
+        // Channel 0 stays in order behind its bulk data, while channel 1 goes ahead of it
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop())
+            .map(|packet| format!("{packet:?}").split('(').next().unwrap().to_string())
+            .collect();
+        assert_eq!(order, ["Ping", "Ack", "Channel", "Binary", "Text", "Close"]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn text_goes_ahead_of_bulk_data_on_other_channels() {
+        let mut queue = PriorityQueue::new();
+        for _ in 0..3 {
+            queue.push(ProxyPacket::Binary(Bytes::from(vec![0; 1000])).on_channel(1));
+        }
+        queue.push(ProxyPacket::Text("publish".into()).on_channel(2));
+        queue.push(ProxyPacket::Binary(Bytes::from(vec![0; 1000])).on_channel(2));
+        queue.push(ProxyPacket::Text("subscribe".into()).on_channel(1));
+
+        let order: Vec<_> = std::iter::from_fn(|| queue.pop())
+            .map(|packet| match packet.into_channel() {
+                (channel, ProxyPacket::Text(text)) => format!("{channel} {text}"),
+                (channel, _) => format!("{channel} bulk"),
+            })
+            .collect();
+
+        // A `Text` only has to wait on bulk data queued up before it on its own channel
+        assert_eq!(
+            order,
+            [
+                "2 publish",
+                "1 bulk",
+                "1 bulk",
+                "1 bulk",
+                "1 subscribe",
+                "2 bulk"
+            ]
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::task::AtomicWaker;
+use futures::Stream;
+
+use crate::ProxyPacket;
+
+/// How long to wait for an `Ack` before resending everything that hasn't been acknowledged
+pub const DEFAULT_RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Packets that can be waiting on an `Ack` before no more data is taken to send
+pub const MAX_UNACKED_PACKETS: usize = 64;
+
+/// Returns true if sequence number `a` comes before `b`, allowing for wraparound
+fn seq_before(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) > 0
+}
+
+/// State for reliable delivery over one USB link connection
+///
+/// Every data packet sent is wrapped in a `Sequenced` packet and kept until the other end
+/// acknowledges it. The other end only accepts packets in order, and answers every `Sequenced`
+/// packet with a cumulative `Ack`, so anything lost along the way (e.g. dropped for a bad
+/// checksum) gets resent along with everything after it.
+///
+/// Once [`MAX_UNACKED_PACKETS`] are waiting on an `Ack`, an [`UnackedGate`] stops taking data until
+/// the other end catches up, so a link that keeps losing packets doesn't pile up more to resend.
+///
+/// This does no I/O itself, so it can be shared between the reading and writing halves of a link.
+#[derive(Debug)]
+pub struct ReliableLink {
+    retransmit_timeout: Duration,
+    /// Sequence number for the next packet sent
+    next_send_seq: u32,
+    /// Packets sent but not yet acknowledged, oldest first
+    unacked: VecDeque<(u32, ProxyPacket)>,
+    /// When to resend the unacknowledged packets if no `Ack` shows up before then
+    retransmit_at: Option<Instant>,
+    /// Sequence number of the next packet expected from the other end
+    next_recv_seq: u32,
+    /// Wakes the gate waiting for room in the window
+    waker: AtomicWaker,
+}
+
+impl ReliableLink {
+    pub fn new(retransmit_timeout: Duration) -> Self {
+        ReliableLink {
+            retransmit_timeout,
+            next_send_seq: 0,
+            unacked: VecDeque::new(),
+            retransmit_at: None,
+            next_recv_seq: 0,
+            waker: AtomicWaker::new(),
+        }
+    }
+
+    /// Returns true if no more data should be sent until the other end acknowledges some of it
+    pub fn is_full(&self) -> bool {
+        self.unacked.len() >= MAX_UNACKED_PACKETS
+    }
+
+    /// Assigns `packet` the next sequence number, and returns the `Sequenced` packet to write
+    pub fn wrap(&mut self, packet: ProxyPacket) -> ProxyPacket {
+        let seq = self.next_send_seq;
+        self.next_send_seq = seq.wrapping_add(1);
+
+        if self.unacked.is_empty() {
+            self.retransmit_at = Some(Instant::now() + self.retransmit_timeout);
+        }
+        self.unacked.push_back((seq, packet.clone()));
+
+        ProxyPacket::Sequenced(seq, Box::new(packet))
+    }
+
+    /// Handles an `Ack` from the other end, which has received everything before `next_seq`
+    pub fn on_ack(&mut self, next_seq: u32) {
+        let before = self.unacked.len();
+
+        while let Some((seq, _)) = self.unacked.front() {
+            if !seq_before(*seq, next_seq) {
+                break;
+            }
+            self.unacked.pop_front();
+        }
+
+        // Only push the retransmit back if the other end is actually making progress
+        if self.unacked.is_empty() {
+            self.retransmit_at = None;
+        } else if self.unacked.len() != before {
+            self.retransmit_at = Some(Instant::now() + self.retransmit_timeout);
+        }
+
+        if !self.is_full() {
+            self.waker.wake();
+        }
+    }
+
+    /// Returns the packets to resend if nothing has been acknowledged for a while, oldest first
+    pub fn due_retransmits(&mut self, now: Instant) -> Vec<ProxyPacket> {
+        match self.retransmit_at {
+            Some(at) if at <= now => {
+                self.retransmit_at = Some(now + self.retransmit_timeout);
+
+                self.unacked
+                    .iter()
+                    .map(|(seq, packet)| ProxyPacket::Sequenced(*seq, Box::new(packet.clone())))
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Handles a `Sequenced` packet from the other end
+    ///
+    /// Returns the packet if it is the next one in order (duplicates and packets after a gap are
+    /// dropped), along with the `Ack` to send back either way
+    pub fn receive(&mut self, seq: u32, packet: ProxyPacket) -> (Option<ProxyPacket>, ProxyPacket) {
+        let delivered = if seq == self.next_recv_seq {
+            self.next_recv_seq = seq.wrapping_add(1);
+            Some(packet)
+        } else {
+            None
+        };
+
+        (delivered, ProxyPacket::Ack(self.next_recv_seq))
+    }
+}
+
+/// Data packets waiting on room in the window of unacknowledged packets, see [`ReliableLink`]
+///
+/// Packets already taken can still go out in pieces, so the window can run over by a message or two,
+/// but nothing new is pulled out of the stream underneath until it's back under the limit
+#[derive(Debug)]
+pub struct UnackedGate<S> {
+    stream: S,
+    reliable: Arc<Mutex<ReliableLink>>,
+}
+
+impl<S> UnackedGate<S> {
+    pub fn new(reliable: Arc<Mutex<ReliableLink>>, stream: S) -> Self {
+        UnackedGate { stream, reliable }
+    }
+}
+
+impl<S: Stream + Unpin> Stream for UnackedGate<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        {
+            // Registering under the lock means an `Ack` can't slip in between the check and the wait
+            let reliable = self.reliable.lock().unwrap();
+            if reliable.is_full() {
+                reliable.waker.register(cx.waker());
+                return Poll::Pending;
+            }
+        }
+
+        Pin::new(&mut self.stream).poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{stream, FutureExt, StreamExt};
+
+    use super::*;
+
+    fn text(packet: &ProxyPacket) -> &str {
+        match packet {
+            ProxyPacket::Text(text) => text,
+            packet => panic!("not text: {packet:?}"),
+        }
+    }
+
+    /// Passes a `Sequenced` packet to `receiver`, returning what it delivers and the `Ack` it sends back
+    fn deliver(receiver: &mut ReliableLink, packet: ProxyPacket) -> (Option<ProxyPacket>, u32) {
+        let ProxyPacket::Sequenced(seq, packet) = packet else {
+            panic!("not sequenced: {packet:?}");
+        };
+
+        match receiver.receive(seq, *packet) {
+            (delivered, ProxyPacket::Ack(next_seq)) => (delivered, next_seq),
+            (_, ack) => panic!("not an ack: {ack:?}"),
+        }
+    }
+
+    #[test]
+    fn lost_packets_are_resent_in_order() {
+        let timeout = Duration::from_millis(10);
+        let mut sender = ReliableLink::new(timeout);
+        let mut receiver = ReliableLink::new(timeout);
+
+        let first = sender.wrap(ProxyPacket::Text("0".into()));
+        let _lost = sender.wrap(ProxyPacket::Text("1".into()));
+        let third = sender.wrap(ProxyPacket::Text("2".into()));
+
+        let (delivered, ack) = deliver(&mut receiver, first);
+        assert_eq!(text(&delivered.unwrap()), "0");
+        sender.on_ack(ack);
+        assert_eq!(sender.unacked.len(), 2);
+
+        // Anything after the gap is dropped until the gap is filled
+        let (delivered, ack) = deliver(&mut receiver, third);
+        assert!(delivered.is_none());
+        assert_eq!(ack, 1);
+
+        assert!(sender.due_retransmits(Instant::now()).is_empty());
+        let resent = sender.due_retransmits(Instant::now() + 2 * timeout);
+        assert_eq!(resent.len(), 2);
+
+        let mut delivered = Vec::new();
+        for packet in resent {
+            let (packet, ack) = deliver(&mut receiver, packet);
+            delivered.extend(packet);
+            sender.on_ack(ack);
+        }
+
+        assert_eq!(delivered.iter().map(text).collect::<Vec<_>>(), ["1", "2"]);
+        assert_eq!(sender.unacked.len(), 0);
+        assert!(sender.due_retransmits(Instant::now() + 10 * timeout).is_empty());
+    }
+
+    #[test]
+    fn duplicates_are_dropped() {
+        let mut sender = ReliableLink::new(DEFAULT_RETRANSMIT_TIMEOUT);
+        let mut receiver = ReliableLink::new(DEFAULT_RETRANSMIT_TIMEOUT);
+
+        let packet = sender.wrap(ProxyPacket::Text("once".into()));
+        assert!(deliver(&mut receiver, packet.clone()).0.is_some());
+        let (delivered, ack) = deliver(&mut receiver, packet);
+        assert!(delivered.is_none());
+        assert_eq!(ack, 1);
+    }
+
+    #[test]
+    fn sequence_numbers_wrap_around() {
+        assert!(seq_before(u32::MAX, 0));
+        assert!(!seq_before(0, u32::MAX));
+        assert!(!seq_before(5, 5));
+
+        let mut sender = ReliableLink::new(DEFAULT_RETRANSMIT_TIMEOUT);
+        sender.next_send_seq = u32::MAX;
+        sender.wrap(ProxyPacket::Ping(0));
+        sender.wrap(ProxyPacket::Ping(1));
+
+        sender.on_ack(0);
+        assert_eq!(sender.unacked.len(), 1);
+        sender.on_ack(1);
+        assert_eq!(sender.unacked.len(), 0);
+    }
+
+    #[test]
+    fn no_data_is_taken_while_the_window_is_full() {
+        let reliable = Arc::new(Mutex::new(ReliableLink::new(DEFAULT_RETRANSMIT_TIMEOUT)));
+        let mut gate = UnackedGate::new(reliable.clone(), stream::iter(0..));
+
+        for _ in 0..MAX_UNACKED_PACKETS {
+            assert!(gate.next().now_or_never().is_some());
+            reliable.lock().unwrap().wrap(ProxyPacket::Ping(0));
+        }
+        assert!(reliable.lock().unwrap().is_full());
+        assert!(gate.next().now_or_never().is_none());
+
+        // An `Ack` for even one packet makes room for another
+        reliable.lock().unwrap().on_ack(1);
+        assert_eq!(gate.next().now_or_never(), Some(Some(MAX_UNACKED_PACKETS)));
+    }
+}
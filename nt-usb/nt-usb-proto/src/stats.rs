@@ -0,0 +1,215 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{ClockEstimate, ProtoError};
+
+/// Counters for one USB link, updated by its [`ProxyCodec`](crate::ProxyCodec)
+///
+/// Shared behind an `Arc`, so they can be read while the codec is busy inside a `Framed`, and carried
+/// over from one connection to the next so they cover the whole run
+#[derive(Debug, Default)]
+pub struct LinkStats {
+    frames_sent: AtomicU64,
+    frames_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    decode_errors: AtomicU64,
+    checksum_failures: AtomicU64,
+    frames_too_large: AtomicU64,
+    auth_failures: AtomicU64,
+    reconnects: AtomicU64,
+    /// Milliseconds since the Unix epoch, or 0 if nothing has been sent yet
+    last_sent_ms: AtomicU64,
+    /// Milliseconds since the Unix epoch, or 0 if nothing has been received yet
+    last_received_ms: AtomicU64,
+    /// Whether `rtt_us` and `clock_offset_us` hold an estimate for the current connection
+    clock_synced: AtomicBool,
+    rtt_us: AtomicU64,
+    clock_offset_us: AtomicI64,
+    /// Latency of the last timestamped frame received, or 0 if there hasn't been one
+    latency_us: AtomicU64,
+}
+
+/// The counters from [`LinkStats`] at one point in time
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkStatsSnapshot {
+    pub frames_sent: u64,
+    pub frames_received: u64,
+    /// Every byte written to the link, framing included
+    pub bytes_sent: u64,
+    /// Every byte read from the link, including garbage between frames
+    pub bytes_received: u64,
+    /// Frames that arrived intact, but didn't hold a packet this build could decode
+    pub decode_errors: u64,
+    /// Frames that didn't make it across intact
+    pub checksum_failures: u64,
+    /// Frames dropped for being bigger than the maximum frame size, in either direction
+    pub frames_too_large: u64,
+    /// Frames dropped for not being signed with the link's key
+    pub auth_failures: u64,
+    /// Times the link went down after being established
+    pub reconnects: u64,
+    /// When a frame was last written, in milliseconds since the Unix epoch
+    pub last_sent_ms: Option<u64>,
+    /// When a packet was last received, in milliseconds since the Unix epoch
+    pub last_received_ms: Option<u64>,
+    /// How long the quickest recent round trip took, in microseconds
+    pub rtt_us: Option<u64>,
+    /// How far ahead of this end's clock the other end's is, in microseconds
+    pub clock_offset_us: Option<i64>,
+    /// How long the last timestamped frame took to get here from the other end, in microseconds
+    pub latency_us: Option<u64>,
+}
+
+impl LinkStats {
+    pub(crate) fn record_sent(&self, bytes: usize) {
+        self.frames_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_sent_ms.store(now_ms(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_received(&self) {
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+        self.last_received_ms.store(now_ms(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_bytes_received(&self, bytes: usize) {
+        self.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a recoverable error against the frame it came from
+    pub(crate) fn record_error(&self, e: &ProtoError) {
+        let counter = match e {
+            ProtoError::ChecksumMismatch => &self.checksum_failures,
+            ProtoError::FrameTooLarge { .. } => &self.frames_too_large,
+            ProtoError::AuthenticationFailed | ProtoError::ReplayedFrame => &self.auth_failures,
+            _ => &self.decode_errors,
+        };
+
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a link going down, for whoever is about to reopen it
+    pub fn record_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Keeps the latest estimate from a [`ClockSync`](crate::ClockSync), which timestamped frames are
+    /// measured against from then on
+    pub fn record_clock(&self, estimate: ClockEstimate) {
+        self.rtt_us.store(estimate.rtt_us, Ordering::Relaxed);
+        self.clock_offset_us.store(estimate.offset_us, Ordering::Relaxed);
+        self.clock_synced.store(true, Ordering::Release);
+    }
+
+    /// Forgets the clock estimate, for when the other end has restarted and its clock with it
+    pub fn clear_clock(&self) {
+        self.clock_synced.store(false, Ordering::Release);
+        self.latency_us.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn clock_estimate(&self) -> Option<ClockEstimate> {
+        self.clock_synced
+            .load(Ordering::Acquire)
+            .then(|| ClockEstimate {
+                rtt_us: self.rtt_us.load(Ordering::Relaxed),
+                offset_us: self.clock_offset_us.load(Ordering::Relaxed),
+            })
+    }
+
+    pub(crate) fn record_latency(&self, latency_us: u64) {
+        // 0 is taken to mean nothing was measured, and a microsecond either way is well within the error
+        self.latency_us.store(latency_us.max(1), Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> LinkStatsSnapshot {
+        let nonzero = |counter: &AtomicU64| match counter.load(Ordering::Relaxed) {
+            0 => None,
+            value => Some(value),
+        };
+
+        let clock = self.clock_estimate();
+
+        LinkStatsSnapshot {
+            frames_sent: self.frames_sent.load(Ordering::Relaxed),
+            frames_received: self.frames_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            decode_errors: self.decode_errors.load(Ordering::Relaxed),
+            checksum_failures: self.checksum_failures.load(Ordering::Relaxed),
+            frames_too_large: self.frames_too_large.load(Ordering::Relaxed),
+            auth_failures: self.auth_failures.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            last_sent_ms: nonzero(&self.last_sent_ms),
+            last_received_ms: nonzero(&self.last_received_ms),
+            rtt_us: clock.map(|clock| clock.rtt_us),
+            clock_offset_us: clock.map(|clock| clock.offset_us),
+            latency_us: nonzero(&self.latency_us),
+        }
+    }
+}
+
+impl fmt::Display for LinkStatsSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} frames ({} bytes) sent, {} frames ({} bytes) received, {} decode errors, {} checksum failures, {} oversized frames, {} unauthenticated frames, {} reconnects",
+            self.frames_sent,
+            self.bytes_sent,
+            self.frames_received,
+            self.bytes_received,
+            self.decode_errors,
+            self.checksum_failures,
+            self.frames_too_large,
+            self.auth_failures,
+            self.reconnects,
+        )?;
+
+        if let Some(rtt_us) = self.rtt_us {
+            write!(f, ", {}us round trip", rtt_us)?;
+        }
+
+        if let Some(latency_us) = self.latency_us {
+            write!(f, ", {}us latency", latency_us)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Keeps the JSON file at `path` up to date with `stats`, rewriting it every `interval`
+///
+/// Only the first of a run of failed writes is passed to `on_error`, rather than one every interval
+pub async fn write_stats_file(
+    stats: Arc<LinkStats>,
+    path: PathBuf,
+    interval: Duration,
+    mut on_error: impl FnMut(io::Error),
+) {
+    let mut warned = false;
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let json = serde_json::to_string_pretty(&stats.snapshot()).unwrap();
+        match tokio::fs::write(&path, json).await {
+            Ok(_) => warned = false,
+            Err(e) if !warned => {
+                on_error(e);
+                warned = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| now.as_millis() as u64)
+}
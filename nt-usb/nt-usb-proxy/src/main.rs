@@ -1,24 +1,277 @@
-use std::time::Duration;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use ansi_term::Colour;
 use rand::Rng;
 use serde::Deserialize;
 
 use futures::{future::select, pin_mut, SinkExt};
-use futures_channel::mpsc::{UnboundedReceiver, UnboundedSender};
-use futures_util::{future::try_join_all, StreamExt};
+use futures_channel::mpsc::{Receiver, Sender, UnboundedReceiver, UnboundedSender};
+use futures_util::{future::try_join_all, stream::FusedStream, StreamExt};
 
-use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio_serial::{SerialPort, SerialPortBuilderExt, SerialStream};
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{protocol::CloseFrame, Message},
+};
+use tokio_util::codec::Framed;
+use url::Url;
 
 use serialport::{available_ports, SerialPortType};
 
-use usb_proto::{ProtoReadable, ProtoWriteable, ProxyPacket};
+use usb_proto::{
+    capture_path, perform_handshake, spawn_capture, switch_baud_rate, write_stats_file, BaudRole,
+    Capabilities, CaptureFormat, CaptureRecord, CaptureTap, CloseReason, Connection,
+    ControlCommand, ControlResponse, DeliveryReceipt, FrameAuth, Framing, LinkConfig, LinkDown,
+    LinkInfo, LinkStats, OpenRequest, PendingCommands, ProxyCodec, ProxyPacket, Received,
+    CONTROL_TIMEOUT, DEFAULT_BAUD_RATE, DEFAULT_BAUD_RATES, DEFAULT_COALESCE_WINDOW,
+    DEFAULT_COMPRESSION_THRESHOLD, DEFAULT_CREDIT_WINDOW, DEFAULT_FRAGMENT_SIZE,
+    DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_LINK_TIMEOUT, DEFAULT_MAX_FRAME_SIZE,
+    DEFAULT_RETRANSMIT_TIMEOUT, NT4_SUBPROTOCOL,
+};
+
+/// Identifies this program to the console during the USB link handshake
+const BUILD_VERSION: &str = concat!(env!("CARGO_PKG_NAME"), " ", env!("CARGO_PKG_VERSION"));
 
 #[derive(Deserialize, Clone)]
 struct ProxyConfig {
+    /// NT4 WS url to connect to, though consoles that send `Open` packets only take the server from it
     url: String,
     serial_port: String,
+    /// Baud rate to open the port at, which the console has to open its end at too
+    #[serde(default = "default_serial_baud")]
     serial_baud: u32,
+    /// Baud rates the port can run at, the fastest of which the console can too is switched to after
+    /// connecting (`[]` to stay at `serial_baud`)
+    #[serde(default = "default_baud_rates")]
+    baud_rates: Vec<u32>,
+    #[serde(default)]
+    framing: Framing,
+    /// How often to send a heartbeat to the console, in milliseconds
+    #[serde(default = "default_heartbeat_interval_ms")]
+    heartbeat_interval_ms: u64,
+    /// How long the console can stay silent before the link is declared dead, in milliseconds
+    #[serde(default = "default_link_timeout_ms")]
+    link_timeout_ms: u64,
+    /// Whether to sequence, acknowledge and resend packets (only used if the console turns it on too)
+    #[serde(default = "default_reliable")]
+    reliable: bool,
+    /// How long to wait for the console to acknowledge packets before resending them, in milliseconds
+    #[serde(default = "default_retransmit_timeout_ms")]
+    retransmit_timeout_ms: u64,
+    /// Whether to hold data back until the console has room for it (only used if the console turns it on too,
+    /// along with `reliable`)
+    #[serde(default = "default_flow_control")]
+    flow_control: bool,
+    /// How many data packets from the console can be waiting to be passed on, and how far ahead of us it can get
+    #[serde(default = "default_credit_window")]
+    credit_window: u32,
+    /// Largest frame payload to send to or accept from the console, in bytes
+    #[serde(default = "default_max_frame_size")]
+    max_frame_size: u32,
+    /// Whether to compress large packets (only used if the console turns it on too)
+    #[serde(default = "default_compression")]
+    compression: bool,
+    /// Smallest text or binary packet worth compressing, in bytes
+    #[serde(default = "default_compression_threshold")]
+    compression_threshold: usize,
+    /// Largest piece of a big binary packet to send at once, in bytes (only used if the console turns
+    /// fragmentation on too)
+    #[serde(default = "default_fragment_size")]
+    fragment_size: usize,
+    /// Whether to stamp frames with when they were sent, to measure how long they take to cross the link
+    /// (only used if the console turns it on too)
+    #[serde(default = "default_timestamps")]
+    timestamps: bool,
+    /// Whether to hold small packets back for a moment so several can share a frame (only used if the
+    /// console turns it on too)
+    #[serde(default = "default_coalescing")]
+    coalescing: bool,
+    /// Longest a small packet is held back waiting for others to share its frame, in milliseconds
+    #[serde(default = "default_coalesce_window_ms")]
+    coalesce_window_ms: u64,
+    /// Shared secret every frame to and from the console is signed with, which has to match theirs (`null`
+    /// to send and accept unsigned frames). This keeps out anything without the key, and frames recorded
+    /// off the link and played back into it once the handshake is over.
+    #[serde(default)]
+    auth_key: Option<String>,
+    /// Whether to take commands for the console from stdin, one per line, e.g. `report_stats` or
+    /// `set_log_level debug` (only used if the console turns it on too)
+    #[serde(default = "default_remote_control")]
+    remote_control: bool,
+    /// Whether to pass WS pings and pongs between the NT server and the console (only used if the
+    /// console turns it on too), rather than answering the server's pings here
+    #[serde(default = "default_forward_ws_ping")]
+    forward_ws_ping: bool,
+    /// Where to keep the latest USB link stats as JSON, for looking at after a match (`null` to not bother)
+    #[serde(default = "default_stats_file")]
+    stats_file: Option<String>,
+    /// How often to update the stats file, in milliseconds
+    #[serde(default = "default_stats_interval_ms")]
+    stats_interval_ms: u64,
+    /// Folder to record every byte that crosses the USB link into, one capture file per run, for
+    /// debugging from afterward (`null` to not bother)
+    #[serde(default)]
+    capture_dir: Option<String>,
+    /// File format to record the USB link in, `native` or `pcapng` (for Wireshark)
+    #[serde(default)]
+    capture_format: CaptureFormat,
+}
+
+impl ProxyConfig {
+    fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis(self.heartbeat_interval_ms)
+    }
+
+    fn link_timeout(&self) -> Duration {
+        Duration::from_millis(self.link_timeout_ms)
+    }
+
+    fn retransmit_timeout(&self) -> Duration {
+        Duration::from_millis(self.retransmit_timeout_ms)
+    }
+
+    fn coalesce_window(&self) -> Duration {
+        Duration::from_millis(self.coalesce_window_ms)
+    }
+
+    fn stats_interval(&self) -> Duration {
+        Duration::from_millis(self.stats_interval_ms)
+    }
+
+    fn link_config(&self) -> LinkConfig {
+        LinkConfig {
+            heartbeat_interval: self.heartbeat_interval(),
+            link_timeout: self.link_timeout(),
+            retransmit_timeout: self.retransmit_timeout(),
+            credit_window: self.credit_window,
+            compression_threshold: self.compression_threshold,
+            fragment_size: self.fragment_size,
+            coalesce_window: self.coalesce_window(),
+            baud_role: BaudRole::Leader,
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        let mut capabilities = Capabilities::HEARTBEAT
+            | Capabilities::MULTIPLEX
+            | Capabilities::OPEN
+            | Capabilities::FRAGMENTATION;
+
+        if self.reliable {
+            capabilities = capabilities | Capabilities::RELIABLE;
+        }
+
+        if self.compression {
+            capabilities = capabilities | Capabilities::COMPRESSION;
+        }
+
+        if !self.baud_rates.is_empty() {
+            capabilities = capabilities | Capabilities::BAUD_SWITCH;
+        }
+
+        if self.flow_control {
+            capabilities = capabilities | Capabilities::FLOW_CONTROL;
+        }
+
+        if self.timestamps {
+            capabilities = capabilities | Capabilities::TIMESTAMPS;
+        }
+
+        if self.coalescing {
+            capabilities = capabilities | Capabilities::BATCHING;
+        }
+
+        if self.remote_control {
+            capabilities = capabilities | Capabilities::CONTROL;
+        }
+
+        if self.forward_ws_ping {
+            capabilities = capabilities | Capabilities::WS_PING;
+        }
+
+        capabilities
+    }
+}
+
+fn default_serial_baud() -> u32 {
+    DEFAULT_BAUD_RATE
+}
+
+fn default_baud_rates() -> Vec<u32> {
+    DEFAULT_BAUD_RATES.to_vec()
+}
+
+fn default_heartbeat_interval_ms() -> u64 {
+    DEFAULT_HEARTBEAT_INTERVAL.as_millis() as u64
+}
+
+fn default_link_timeout_ms() -> u64 {
+    DEFAULT_LINK_TIMEOUT.as_millis() as u64
+}
+
+fn default_reliable() -> bool {
+    true
+}
+
+fn default_retransmit_timeout_ms() -> u64 {
+    DEFAULT_RETRANSMIT_TIMEOUT.as_millis() as u64
+}
+
+fn default_flow_control() -> bool {
+    true
+}
+
+fn default_credit_window() -> u32 {
+    DEFAULT_CREDIT_WINDOW
+}
+
+fn default_max_frame_size() -> u32 {
+    DEFAULT_MAX_FRAME_SIZE
+}
+
+fn default_compression() -> bool {
+    true
+}
+
+fn default_compression_threshold() -> usize {
+    DEFAULT_COMPRESSION_THRESHOLD
+}
+
+fn default_fragment_size() -> usize {
+    DEFAULT_FRAGMENT_SIZE
+}
+
+fn default_timestamps() -> bool {
+    true
+}
+
+fn default_coalescing() -> bool {
+    true
+}
+
+fn default_coalesce_window_ms() -> u64 {
+    DEFAULT_COALESCE_WINDOW.as_millis() as u64
+}
+
+fn default_remote_control() -> bool {
+    true
+}
+
+fn default_forward_ws_ping() -> bool {
+    true
+}
+
+fn default_stats_file() -> Option<String> {
+    Some(String::from("./proxy.stats.json"))
+}
+
+fn default_stats_interval_ms() -> u64 {
+    1000
 }
 
 #[tokio::main]
@@ -42,35 +295,211 @@ async fn main() -> ! {
             } else {
                 "/dev/ttyUSB0"
             }),
-            serial_baud: 115_200,
+            serial_baud: default_serial_baud(),
+            baud_rates: default_baud_rates(),
+            framing: Framing::default(),
+            heartbeat_interval_ms: default_heartbeat_interval_ms(),
+            link_timeout_ms: default_link_timeout_ms(),
+            reliable: default_reliable(),
+            retransmit_timeout_ms: default_retransmit_timeout_ms(),
+            flow_control: default_flow_control(),
+            credit_window: default_credit_window(),
+            max_frame_size: default_max_frame_size(),
+            compression: default_compression(),
+            compression_threshold: default_compression_threshold(),
+            fragment_size: default_fragment_size(),
+            timestamps: default_timestamps(),
+            coalescing: default_coalescing(),
+            coalesce_window_ms: default_coalesce_window_ms(),
+            auth_key: None,
+            remote_control: default_remote_control(),
+            forward_ws_ping: default_forward_ws_ping(),
+            stats_file: default_stats_file(),
+            stats_interval_ms: default_stats_interval_ms(),
+            capture_dir: None,
+            capture_format: CaptureFormat::default(),
         },
     };
 
     // Create a full duplex channel between the two main async tasks
+    // Nothing the console sends waits on the WS side, so only credit limits how much of it piles up
     let (usb_tx, usb_rx) = futures_channel::mpsc::unbounded();
-    let (ws_tx, ws_rx) = futures_channel::mpsc::unbounded();
+    let (ws_tx, ws_rx) = futures_channel::mpsc::channel(config.credit_window as usize);
+
+    // Keep count of how the USB link is doing across reconnects
+    let stats = Arc::new(LinkStats::default());
+
+    // Record the USB link from the first byte, if asked to
+    let capture = start_capture(&config);
+
+    // Commands typed in for the console, waiting to be sent over the link
+    let (control_tx, control_rx) = futures_channel::mpsc::unbounded();
+    if config.remote_control {
+        tokio::spawn(read_control_commands(control_tx));
+    }
 
     // Spawn the async tasks
-    let ws_future = tokio::spawn(create_ws_client(config.clone(), ws_tx, usb_rx));
-    let usb_future = tokio::spawn(create_usb_master(config, usb_tx, ws_rx));
+    let ws_future = tokio::spawn(route_ws_channels(config.clone(), ws_tx, usb_rx));
+    let stats_future = tokio::spawn(write_stats(config.clone(), stats.clone()));
+    let usb_future = tokio::spawn(create_usb_master(
+        config, stats, capture, usb_tx, ws_rx, control_rx,
+    ));
 
-    // Run both tasks concurrently
-    try_join_all(vec![ws_future, usb_future]).await.unwrap();
+    // Run all tasks concurrently
+    try_join_all(vec![ws_future, stats_future, usb_future]).await.unwrap();
 
     panic!("unreachable");
 }
 
-/// Creates the WS half of the proxy
+/// Hands each packet from the USB port to the WS connection for its channel
+///
+/// Nothing connects to the NT server until the console asks for it with an `Open` packet, which replaces
+/// whatever connection the channel had before. Consoles that can't send `Open` packets get connected to
+/// the configured url the first time they use a channel instead.
+///
+/// Each packet's receipt goes along with it, so the console only gets credit back once its WS connection
+/// has written it.
+async fn route_ws_channels(
+    config: ProxyConfig,
+    tx: Sender<ProxyPacket>,
+    mut rx: UnboundedReceiver<(ProxyPacket, DeliveryReceipt)>,
+) {
+    let mut channels = HashMap::new();
+
+    while let Some((packet, receipt)) = rx.next().await {
+        let (channel, packet) = packet.into_channel();
+
+        match packet {
+            // Dropping the old connection's sender shuts it down, and the `Open` itself is used up here
+            ProxyPacket::Open(request) => {
+                channels.insert(channel, spawn_ws_channel(&config, channel, Some(request), &tx));
+            }
+            packet => {
+                let channel_tx = channels
+                    .entry(channel)
+                    .or_insert_with(|| spawn_ws_channel(&config, channel, None, &tx));
+
+                // Never wait on one connection, or every other channel would wait with it
+                // The connection gave up (e.g. on a bad `Open` request), so the next packet starts a fresh one
+                if channel_tx.unbounded_send((packet, receipt)).is_err() {
+                    channels.remove(&channel);
+                    eprintln!(
+                        "{}",
+                        Colour::Yellow.paint(format!(
+                            "Dropped a packet for channel {}, which has no WS connection.",
+                            channel
+                        ))
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Spawns the WS connection for `channel`, returning where to send its packets
+fn spawn_ws_channel(
+    config: &ProxyConfig,
+    channel: u8,
+    request: Option<OpenRequest>,
+    tx: &Sender<ProxyPacket>,
+) -> UnboundedSender<(ProxyPacket, DeliveryReceipt)> {
+    let (channel_tx, channel_rx) = futures_channel::mpsc::unbounded();
+
+    tokio::spawn(create_ws_client(
+        config.clone(),
+        channel,
+        request,
+        tx.clone(),
+        channel_rx,
+    ));
+
+    channel_tx
+}
+
+/// Builds the url an `Open` request asks for, on the NT server at `base`
+///
+/// Only the path and query come from the console, so anything that would point somewhere else (e.g. a
+/// full url, or a `//host` path) is refused
+fn request_url(base: &str, request: &OpenRequest) -> Option<Url> {
+    let path = request.path_and_query();
+    if !path.starts_with('/') {
+        return None;
+    }
+
+    let base = Url::parse(base).ok()?;
+    let url = base.join(&path).ok()?;
+
+    (url.origin() == base.origin()).then_some(url)
+}
+
+/// Builds the url for a channel that never sent an `Open` request, from the configured url at `base`
+///
+/// Each channel still needs its own NT4 client name, so every channel but 0 gets its number appended
+/// to the last part of the path, keeping any query as it is
+fn channel_url(base: &str, channel: u8) -> Option<Url> {
+    let mut url = Url::parse(base).ok()?;
+    if channel == 0 {
+        return Some(url);
+    }
+
+    // The client name is the last part of the path, so there has to be one to append to
+    let path = url.path().trim_end_matches('/').to_string();
+    if path.is_empty() {
+        return None;
+    }
+
+    url.set_path(&format!("{path}-{channel}"));
+    Some(url)
+}
+
+/// Creates the WS half of the proxy for one channel, until the sender for `rx` is dropped
+///
+/// The console picks the path on the NT server with its `Open` request. Without one, the url comes
+/// from [`channel_url`].
 ///
 /// TODO:
-///     - Removed hardcoded connection url
 ///     - Add better error handling
 async fn create_ws_client(
     config: ProxyConfig,
-    tx: UnboundedSender<ProxyPacket>,
-    mut rx: UnboundedReceiver<ProxyPacket>,
+    channel: u8,
+    request: Option<OpenRequest>,
+    mut tx: Sender<ProxyPacket>,
+    mut rx: UnboundedReceiver<(ProxyPacket, DeliveryReceipt)>,
 ) {
-    let url = &config.url;
+    let (url, subprotocols) = match &request {
+        // Only the server's address comes from the configured url
+        Some(request) => {
+            let Some(url) = request_url(&config.url, request) else {
+                eprintln!(
+                    "{}",
+                    Colour::Red.paint(format!(
+                        "Could not build a WS url for NT4 client `{}` on channel {} from path `{}`.",
+                        request.client_name,
+                        channel,
+                        request.path_and_query()
+                    ))
+                );
+                return;
+            };
+
+            (url.to_string(), request.subprotocols.join(", "))
+        }
+        None => {
+            let Some(url) = channel_url(&config.url, channel) else {
+                eprintln!(
+                    "{}",
+                    Colour::Red.paint(format!(
+                        "Could not build a WS url for channel {} from `{}`.",
+                        channel, config.url
+                    ))
+                );
+                return;
+            };
+
+            (url.to_string(), NT4_SUBPROTOCOL.to_string())
+        }
+    };
+    let url = &url;
 
     loop {
         // Generate a random 16 bytes and base64 them to create our unique connection key
@@ -81,7 +510,7 @@ async fn create_ws_client(
             .method("GET")
             .uri(url)
             .header("Sec-WebSocket-Key", ws_key)
-            .header("Sec-WebSocket-Protocol", "networktables.first.wpi.edu")
+            .header("Sec-WebSocket-Protocol", subprotocols.as_str())
             .header("Sec-WebSocket-Version", "13")
             .header("Connection", "Upgrade")
             .header("Upgrade", "websocket")
@@ -106,7 +535,10 @@ async fn create_ws_client(
 
         println!(
             "{}",
-            Colour::Green.paint("WebSocket handshake has been successfully completed")
+            Colour::Green.paint(format!(
+                "WebSocket handshake for channel {} has been successfully completed",
+                channel
+            ))
         );
 
         // Split the WS stream into a read stream and a write stream
@@ -118,9 +550,9 @@ async fn create_ws_client(
                 // Get the message from the ws stream
                 let message = read.next().await;
 
-                // If no message is available, keep looping until one is
+                // The NT server hung up, so reconnect
                 let Some(message) = message else {
-                    continue;
+                    break;
                 };
 
                 let message = match message {
@@ -136,14 +568,25 @@ async fn create_ws_client(
                     }
                 };
 
-                match message {
-                    Message::Text(string) => tx
-                        .unbounded_send(ProxyPacket::Text(string.clone()))
-                        .unwrap(),
-                    Message::Binary(data) => tx.unbounded_send(ProxyPacket::Binary(data)).unwrap(),
-                    Message::Close(_) => tx.unbounded_send(ProxyPacket::Close).unwrap(),
-                    _ => eprintln!("Unimplemented message type: {:?}", message),
+                let packet = match message {
+                    Message::Text(string) => ProxyPacket::Text(string.clone()),
+                    Message::Binary(data) => ProxyPacket::Binary(data.into()),
+                    // Pass on why the NT server closed the connection, so the client can tell too
+                    Message::Close(frame) => ProxyPacket::Close(frame.map(|frame| CloseReason {
+                        code: frame.code.into(),
+                        reason: frame.reason.into_owned(),
+                    })),
+                    // Whether these actually reach the console is up to the USB link
+                    Message::Ping(data) => ProxyPacket::WsPing(data.into()),
+                    Message::Pong(data) => ProxyPacket::WsPong(data.into()),
+                    _ => {
+                        eprintln!("Unimplemented message type: {:?}", message);
+                        continue;
+                    }
                 };
+
+                // Tag the packet with its channel so the console can tell the connections apart
+                tx.send(packet.on_channel(channel)).await.unwrap();
             }
         };
 
@@ -153,12 +596,16 @@ async fn create_ws_client(
                 // Get the next packet from the usb client
                 let usb_packet = rx.next().await;
 
-                // If no packet is available, keep looping until one is
-                let Some(packet) = usb_packet else {
-                    continue;
+                // The console replaced this connection with a new one
+                // Holding on to the receipt until the packet is written keeps the console from getting ahead
+                let Some((packet, _receipt)) = usb_packet else {
+                    break;
                 };
 
-                let ws_message = packet.into_message();
+                // Link level packets have no WS equivalent
+                let Some(ws_message) = packet.into_message() else {
+                    continue;
+                };
 
                 // Write the packet to the stream
                 let Ok(_) = write.send(ws_message).await else {
@@ -173,8 +620,15 @@ async fn create_ws_client(
         };
 
         // Run both concurrently
-        pin_mut!(ws_to_usb, usb_to_ws);
-        select(ws_to_usb, usb_to_ws).await;
+        {
+            pin_mut!(ws_to_usb, usb_to_ws);
+            select(ws_to_usb, usb_to_ws).await;
+        }
+
+        // Nothing else will ever be sent on this connection, so don't bother reconnecting
+        if rx.is_terminated() {
+            return;
+        }
 
         // Wait the 5 seconds between retry attempts for futures
         tokio::time::sleep(Duration::from_secs(5)).await;
@@ -184,9 +638,18 @@ async fn create_ws_client(
 /// This creates a loop which never ends. It
 async fn create_usb_master(
     config: ProxyConfig,
-    tx: UnboundedSender<ProxyPacket>,
-    mut rx: UnboundedReceiver<ProxyPacket>,
+    stats: Arc<LinkStats>,
+    capture: Option<UnboundedSender<CaptureRecord>>,
+    tx: UnboundedSender<(ProxyPacket, DeliveryReceipt)>,
+    mut rx: Receiver<ProxyPacket>,
+    mut control_rx: UnboundedReceiver<ControlCommand>,
 ) {
+    // Rates that didn't work out are left out of later connections, so they aren't tried again every time
+    let mut baud_rates = config.baud_rates.clone();
+
+    // Commands sent to the console that haven't been answered yet, matched up with their answers by ID
+    let control_sent = Mutex::new(PendingCommands::default());
+
     // Loop continuously while no ports are found or an error condition is met, to always try reconnecting
     loop {
         // Try to enumerate the available ports (if failed, try again)
@@ -263,9 +726,8 @@ async fn create_usb_master(
             continue;
         };
 
-        let Ok(port) = serialport::new(port.port_name.as_str(), config.serial_baud)
-            .timeout(Duration::from_secs(60 * 60))
-            .open() else {
+        let Ok(port) = tokio_serial::new(port.port_name.as_str(), config.serial_baud)
+            .open_native_async() else {
                 eprintln!(
                     "{} {}",
                     Colour::Red.paint(format!("Failed to open serial port `{}` at {} baud.", config.serial_port, config.serial_baud)),
@@ -283,98 +745,440 @@ async fn create_usb_master(
             ))
         );
 
-        let (Ok(mut reader), Ok(mut writer)) = (port.try_clone(), port.try_clone()) else {
-            eprintln!(
-                "{} {}",
-                Colour::Red.paint("Failed to clone serial port for reading and writing."),
-                Colour::White.dimmed().paint("Trying again in 5 seconds...")
-            );
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            continue;
+        // Frame packets going in and out of the port
+        let mut codec =
+            ProxyCodec::new(config.framing, config.max_frame_size).with_stats(stats.clone());
+
+        // Only talk to a console that has the same key, if there is one
+        if let Some(key) = &config.auth_key {
+            codec = codec.with_auth(FrameAuth::new(key.as_bytes()));
+        }
+
+        // Copy everything read from or written to the port into the capture
+        let mut port = CaptureTap::new(port);
+        if let Some(capture) = &capture {
+            port = port.with_sender(capture.clone());
+        }
+        let mut link = Framed::new(port, codec);
+
+        // Make sure the console speaks our protocol before forwarding anything to it
+        let local = LinkInfo::new(BUILD_VERSION, config.capabilities(), config.max_frame_size)
+            .with_baud_rates(baud_rates.clone());
+        let params = match perform_handshake(&mut link, &local, &stats).await {
+            Ok(params) => params,
+            Err(e) => {
+                eprintln!("{}", e);
+                eprintln!(
+                    "{} {}",
+                    Colour::Red.paint("USB link handshake with the console failed."),
+                    Colour::White.dimmed().paint("Trying again in 5 seconds...")
+                );
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
         };
 
-        // Read packets from usb serial and send them to the ws client
-        let usb_to_ws = async {
-            loop {
-                // If there was a reading error, break and retry the connection
-                let Ok(num_bytes) = reader.bytes_to_read() else {
+        // Tie every frame from here on to this connection, so none can be played back into it
+        link.codec_mut().bind_auth(local.session, params.peer.session);
+
+        println!(
+            "{}",
+            Colour::Green.paint(format!(
+                "USB link established with `{}` (protocol v{})",
+                params.peer.build_version, params.protocol_version
+            ))
+        );
+
+        // Move up to the fastest baud rate both ends can manage, falling back if the port can't keep up
+        if let Some(baud_rate) = params.baud_rate {
+            let switched = switch_baud_rate(
+                &mut link,
+                &local,
+                BaudRole::Leader,
+                baud_rate,
+                config.serial_baud,
+                set_baud_rate,
+            )
+            .await;
+
+            match switched {
+                Ok(switched) if switched == baud_rate => println!(
+                    "{}",
+                    Colour::Green.paint(format!("USB link running at {} baud", baud_rate))
+                ),
+                Ok(switched) => {
+                    eprintln!(
+                        "{}",
+                        Colour::Yellow.paint(format!(
+                            "USB link didn't work at {} baud, staying at {} baud",
+                            baud_rate, switched
+                        ))
+                    );
+                    baud_rates.retain(|&rate| rate != baud_rate);
+                }
+                Err(e) => {
+                    eprintln!("{}", e);
                     eprintln!(
                         "{} {}",
-                        Colour::Red.paint("Failed to read bytes from serial."),
+                        Colour::Red.paint("Lost the serial connection while switching baud rates."),
                         Colour::White.dimmed().paint("Trying again in 5 seconds...")
                     );
-                    break
-                 };
-
-                // If there are no bytes to read, continue
-                if num_bytes == 0 {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
                     continue;
                 }
+            }
+        }
 
-                // Read a packet from the stream
-                let packet = match reader.read_packet() {
-                    Ok(p) => p,
-                    Err(e) => {
-                        eprintln!("{:?}", e);
+        // Keep the link running with whatever both ends agreed on, for as long as this connection lasts
+        let (connection, link_rx) =
+            Connection::start(local, params, config.link_config(), stats.clone());
+        connection.configure(link.codec_mut());
+
+        let (mut writer, mut reader) = link.split();
+
+        let control = connection
+            .params()
+            .capabilities
+            .contains(Capabilities::CONTROL);
+
+        // Set if the console restarted, and is already waiting for this end to handshake again
+        let restarted = AtomicBool::new(false);
+
+        // Read packets from usb serial and send them to the ws client
+        let usb_to_ws = async {
+            loop {
+                // Read a packet from the stream, giving up on the console if it goes quiet for too long
+                let packet = match connection.next_packet(&mut reader).await {
+                    Ok(packet) => packet,
+                    Err(LinkDown::Silent(timeout)) => {
+                        eprintln!(
+                            "{} {}",
+                            Colour::Red.paint(format!(
+                                "Console stopped responding (nothing received for {} ms).",
+                                timeout.as_millis()
+                            )),
+                            Colour::White.dimmed().paint("Trying again in 5 seconds...")
+                        );
+                        break;
+                    }
+                    Err(LinkDown::Failed(e)) => {
+                        eprintln!("{}", e);
+                        eprintln!(
+                            "{} {}",
+                            Colour::Red.paint("Lost the serial connection."),
+                            Colour::White.dimmed().paint("Trying again in 5 seconds...")
+                        );
+                        break;
+                    }
+                    Err(LinkDown::Closed) => {
                         eprintln!(
                             "{} {}",
-                            Colour::Red.paint("Failed to read and decode packet from stream."),
+                            Colour::Red.paint("Serial port was closed."),
                             Colour::White.dimmed().paint("Trying again in 5 seconds...")
                         );
                         break;
                     }
                 };
 
-                // Send the packet to the ws client to be sent over the network
-                tx.unbounded_send(packet).unwrap();
+                match connection.receive(packet) {
+                    // Send the packet to the ws client to be sent over the network
+                    // Never wait on the ws client here, or the link would stop being read. Flow
+                    // control keeps the console from sending more than there's room for, when it's on
+                    Received::Data(packet) => {
+                        tx.unbounded_send((packet, connection.receipt())).unwrap();
+                    }
+                    Received::ControlResponse(id, body) => {
+                        let command = match control_sent.lock().unwrap().answer(id) {
+                            Some(command) => format!("`{}`", command),
+                            None => format!("command {}", id),
+                        };
+
+                        match ControlResponse::decode(&body) {
+                            Ok(response @ ControlResponse::Failed { .. }) => eprintln!(
+                                "{}",
+                                Colour::Yellow
+                                    .paint(format!("Console answered {}: {}", command, response))
+                            ),
+                            Ok(response) => println!(
+                                "{}",
+                                Colour::Green
+                                    .paint(format!("Console answered {}: {}", command, response))
+                            ),
+                            Err(e) => eprintln!(
+                                "{}",
+                                Colour::Yellow.paint(format!(
+                                    "Could not read the console's answer to {}: {}",
+                                    command, e
+                                ))
+                            ),
+                        }
+                    }
+                    // Only this end sends commands
+                    Received::ControlRequest(..) => {}
+                    Received::Nothing | Received::Clock(_) => {}
+                    // Reconnect straight away, so everything is agreed on again from scratch
+                    Received::Restarted => {
+                        eprintln!(
+                            "{}",
+                            Colour::Yellow
+                                .paint("Console restarted its end of the USB link, reconnecting.")
+                        );
+                        restarted.store(true, Ordering::Relaxed);
+                        break;
+                    }
+                }
+            }
+        };
+
+        // Send commands typed in for the console over the link, as long as it knows what to do with them
+        let send_commands = async {
+            while let Some(command) = control_rx.next().await {
+                if !control {
+                    eprintln!(
+                        "{}",
+                        Colour::Yellow.paint(format!(
+                            "Console doesn't take commands, so `{}` wasn't sent",
+                            command
+                        ))
+                    );
+                    continue;
+                }
 
-                // Force tokio to let another task work
-                tokio::time::sleep(Duration::from_millis(1)).await;
+                let packet = control_sent.lock().unwrap().send(command, Instant::now());
+                connection.send(packet);
             }
+
+            // Nobody is typing commands in, but the link carries on without them
+            futures::future::pending::<()>().await
         };
 
-        let ws_to_usb = async {
+        // Stop waiting on commands the console is never going to answer
+        let expire_commands = async {
             loop {
-                // Get the next packet from the ws client
-                let ws_packet = rx.next().await;
+                tokio::time::sleep(CONTROL_TIMEOUT / 5).await;
 
-                // If no packet is available, keep looping until one is
-                let Some(packet) = ws_packet else {
-                    continue;
-                };
-
-                // Write the packet to the stream
-                let Ok(_) = writer.write_packet(packet) else {
+                let expired = control_sent
+                    .lock()
+                    .unwrap()
+                    .expire(Instant::now(), CONTROL_TIMEOUT);
+                for command in expired {
                     eprintln!(
-                        "{} {}",
-                        Colour::Red.paint("Failed to encode and write packet to stream."),
-                        Colour::White.dimmed().paint("Trying again in 5 seconds...")
+                        "{}",
+                        Colour::Yellow.paint(format!("Console never answered `{}`", command))
                     );
-                    break
-                };
+                }
             }
         };
 
+        let ws_to_usb = async {
+            let e = connection
+                .write(&mut writer, link_rx, &mut rx, |e| {
+                    eprintln!(
+                        "{}",
+                        Colour::Yellow.paint(format!("Dropped a packet for the console: {}", e))
+                    )
+                })
+                .await;
+
+            eprintln!("{}", e);
+            eprintln!(
+                "{} {}",
+                Colour::Red.paint("Failed to encode and write packet to stream."),
+                Colour::White.dimmed().paint("Trying again in 5 seconds...")
+            );
+        };
+
         // Run both concurrently, and retry on any errors
-        pin_mut!(ws_to_usb, usb_to_ws);
-        select(ws_to_usb, usb_to_ws).await;
+        pin_mut!(ws_to_usb, usb_to_ws, send_commands, expire_commands);
+        select(ws_to_usb, select(usb_to_ws, select(send_commands, expire_commands))).await;
 
-        // Wait the 5 seconds between retry attempts for futures
-        tokio::time::sleep(Duration::from_secs(5)).await;
+        // Answers to anything still waiting went down with the link
+        for command in control_sent.lock().unwrap().clear() {
+            eprintln!(
+                "{}",
+                Colour::Yellow.paint(format!(
+                    "Lost the USB link before the console answered `{}`",
+                    command
+                ))
+            );
+        }
+
+        // Leave a record of how the link was doing before it went down
+        stats.record_reconnect();
+        eprintln!(
+            "{}",
+            Colour::White
+                .dimmed()
+                .paint(format!("USB link stats: {}", stats.snapshot()))
+        );
+
+        // Wait the 5 seconds between retry attempts for futures, unless the console is waiting on us
+        if !restarted.load(Ordering::Relaxed) {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        }
     }
 }
 
 /// Trait to allow ProxyPackets to be converted to tungstenite Messages
+///
+/// Link level packets have no WS equivalent, and convert to `None`
 pub trait IntoMessage: Sized {
-    fn into_message(self) -> Message;
+    fn into_message(self) -> Option<Message>;
 }
 
 impl IntoMessage for ProxyPacket {
-    fn into_message(self) -> Message {
+    fn into_message(self) -> Option<Message> {
         match self {
-            ProxyPacket::Text(string) => Message::text(string),
-            ProxyPacket::Binary(data) => Message::binary(data),
-            ProxyPacket::Close => Message::Close(None),
+            ProxyPacket::Text(string) => Some(Message::text(string)),
+            ProxyPacket::Binary(data) => Some(Message::binary(data)),
+            ProxyPacket::Close(close) => Some(Message::Close(close.map(|close| CloseFrame {
+                code: close.code.into(),
+                reason: close.reason.into(),
+            }))),
+            ProxyPacket::WsPing(data) => Some(Message::Ping(data.into())),
+            ProxyPacket::WsPong(data) => Some(Message::Pong(data.into())),
+            // Link level packets
+            _ => None,
         }
     }
 }
+
+/// Changes the baud rate of the serial port underneath `link`
+fn set_baud_rate(
+    link: &mut Framed<CaptureTap<SerialStream>, ProxyCodec>,
+    baud_rate: u32,
+) -> io::Result<()> {
+    link.get_mut()
+        .get_mut()
+        .set_baud_rate(baud_rate)
+        .map_err(io::Error::from)
+}
+
+/// Opens a new capture file in the capture folder and starts writing the USB link into it, if there is one
+///
+/// Problems with the capture are only warned about, since the link works fine without it
+fn start_capture(config: &ProxyConfig) -> Option<UnboundedSender<CaptureRecord>> {
+    let dir = config.capture_dir.as_ref()?;
+    let path = capture_path(dir, "proxy", config.capture_format);
+
+    let stopped = path.clone();
+    let capture = spawn_capture(&path, config.capture_format, move |e| {
+        eprintln!(
+            "{}",
+            Colour::Yellow.paint(format!(
+                "Stopped capturing to `{}`: {}",
+                stopped.display(),
+                e
+            ))
+        )
+    });
+
+    match capture {
+        Ok(tx) => {
+            println!(
+                "{}",
+                Colour::White
+                    .dimmed()
+                    .paint(format!("Capturing the USB link to `{}`", path.display()))
+            );
+            Some(tx)
+        }
+        Err(e) => {
+            eprintln!(
+                "{}",
+                Colour::Yellow.paint(format!(
+                    "Could not create capture file `{}`: {}",
+                    path.display(),
+                    e
+                ))
+            );
+            None
+        }
+    }
+}
+
+/// Reads commands for the console from stdin, one per line, until stdin is closed
+async fn read_control_commands(control_tx: UnboundedSender<ControlCommand>) {
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match line.parse() {
+            Ok(command) => {
+                if control_tx.unbounded_send(command).is_err() {
+                    return;
+                }
+            }
+            Err(e) => eprintln!("{}", Colour::Yellow.paint(e)),
+        }
+    }
+}
+
+/// Keeps the stats file up to date with the USB link stats, if there is one
+async fn write_stats(config: ProxyConfig, stats: Arc<LinkStats>) {
+    let Some(path) = config.stats_file.clone() else {
+        return;
+    };
+
+    write_stats_file(stats, path.clone().into(), config.stats_interval(), |e| {
+        eprintln!(
+            "{}",
+            Colour::Yellow.paint(format!("Could not write stats to `{}`: {}", path, e))
+        )
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_path(path: &str) -> OpenRequest {
+        let mut request = OpenRequest::new("pi");
+        request.path = Some(path.to_string());
+        request
+    }
+
+    #[test]
+    fn request_urls_stay_on_the_server() {
+        let base = "ws://10.0.0.2:5810/nt/proxy";
+
+        let url = request_url(base, &OpenRequest::new("dash board")).unwrap();
+        assert_eq!(url.as_str(), "ws://10.0.0.2:5810/nt/dash%20board");
+
+        let url = request_url(base, &request_path("/nt/pi?x=1")).unwrap();
+        assert_eq!(url.as_str(), "ws://10.0.0.2:5810/nt/pi?x=1");
+
+        for path in [
+            "ws://evil:5810/nt/pi",
+            "//evil:5810/nt/pi",
+            "/\\evil:5810/nt/pi",
+            "\\\\evil/nt/pi",
+            "nt/pi",
+        ] {
+            assert!(request_url(base, &request_path(path)).is_none(), "{path}");
+        }
+    }
+
+    #[test]
+    fn channel_urls_keep_the_query() {
+        let url = |base, channel| channel_url(base, channel).map(|url| url.to_string());
+
+        assert_eq!(
+            url("ws://10.0.0.2:5810/nt/proxy", 0).unwrap(),
+            "ws://10.0.0.2:5810/nt/proxy"
+        );
+        assert_eq!(
+            url("ws://10.0.0.2:5810/nt/proxy", 2).unwrap(),
+            "ws://10.0.0.2:5810/nt/proxy-2"
+        );
+        assert_eq!(
+            url("ws://10.0.0.2:5810/nt/proxy/?x=1", 2).unwrap(),
+            "ws://10.0.0.2:5810/nt/proxy-2?x=1"
+        );
+        assert!(url("ws://10.0.0.2:5810/", 2).is_none());
+    }
+}